@@ -9,13 +9,18 @@ extern crate rand;
 extern crate time;  
 
 pub use variable::TVar;
-pub use transaction::Transaction;
+pub use transaction::{Transaction, Savepoint};
+pub use transaction::backend::{Backend, set_backend, backend};
+pub use transaction::contention::{ContentionManager, ContentionPolicy, NoBackoff, Backoff, Polka};
+pub use transaction::contention::{set_contention_policy, contention_policy};
+pub use transaction::contention::{set_serialize_after, serialize_after};
 pub use result::*;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use rand::{thread_rng, Rng};
 use time::*;
 use std::mem;
+use std::time::Duration;
 
 pub fn retry<T>() -> StmResult<T> {
     Err(StmError::Retry)
@@ -27,6 +32,91 @@ where F: Fn(&mut Transaction) -> StmResult<T>
     Transaction::run(f)
 }
 
+/// Run a transaction, but give up once `timeout` has passed.
+///
+/// Returns `None` on timeout. Time spent blocked in `retry` counts as well.
+pub fn atomically_timeout<T, F>(timeout: Duration, f: F) -> Option<T>
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_timeout(timeout, f)
+}
+
+/// Run a transaction, but give up after `max_attempts` executions of `f`.
+///
+/// A call to `retry` doesn't block, but uses up an attempt.
+pub fn atomically_bounded<T, F>(max_attempts: usize, f: F) -> Result<T, Exhausted>
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_bounded(max_attempts, f)
+}
+
+/// Run a transaction, that resolves conflicts with `manager`.
+///
+/// Other transactions use the manager selected by `set_contention_policy`.
+pub fn atomically_with<T, F>(manager: &mut ContentionManager, f: F) -> T
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_managed(manager, f)
+}
+
+/// Run a transaction, that only reads.
+///
+/// It sees the vars as they were at its start, so commits of other
+/// transactions don't restart it. Reads are not logged, which makes
+/// it cheaper than `atomically` for transactions, that read many vars.
+/// Writing panics.
+pub fn atomically_read_only<T, F>(f: F) -> T
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_read_only(f)
+}
+
+/// Run a transaction, that traverses a structure before changing it.
+///
+/// Reads before the first write are only validated in pairs, so writes
+/// to parts of the structure, that have been passed, don't restart it.
+/// See `Transaction::run_elastic` for when this is safe.
+pub fn atomically_elastic<T, F>(f: F) -> T
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_elastic(f)
+}
+
+/// Run a transaction under snapshot isolation.
+///
+/// Reads see the vars as they were at its start and only conflicting
+/// writes restart it. Use `TVar::ensure` for reads, that must not change
+/// before the commit. See `Transaction::run_snapshot_isolated`.
+pub fn atomically_snapshot_isolated<T, F>(f: F) -> T
+where F: Fn(&mut Transaction) -> StmResult<T>
+{
+    Transaction::run_snapshot_isolated(f)
+}
+
+/// Abort the transaction with a user error.
+///
+/// All writes are discarded and `atomically_result` returns `err`.
+pub fn abort<T, E>(err: E) -> TransactionResult<T, E> {
+    Err(TransactionError::Abort(err))
+}
+
+/// Run a transaction, that may be aborted with `abort`.
+pub fn atomically_result<T, E, F>(f: F) -> Result<T, E>
+where F: Fn(&mut Transaction) -> TransactionResult<T, E>
+{
+    Transaction::run_result(f)
+}
+
+/// Run a transaction, that may be aborted with `abort` or by an invariant.
+///
+/// Unlike the other functions, that panic, if the transaction would violate
+/// an invariant, this returns the name of the invariant as an error.
+pub fn atomically_checked<T, E, F>(f: F) -> Result<T, CheckedError<E>>
+where F: Fn(&mut Transaction) -> TransactionResult<T, E>
+{
+    Transaction::run_checked(f)
+}
+
 #[derive(PartialEq)]
 #[derive(Clone)]
 #[derive(Debug)]
@@ -1902,6 +1992,342 @@ fn ll_insertion_random_large_single_lock() {
     println!("{} seconds for whatever you did.", start.to(end));
 }
 
+#[test]
+fn validation_with_address_reuse() {
+    test::each_backend(validation_with_address_reuse_stm);
+}
+
+fn validation_with_address_reuse_stm() {
+    // Writers replace both vars with freshly allocated values of the same
+    // size all the time, so new values keep landing on the addresses of
+    // freed ones. Readers must still never see the two vars out of sync.
+    let a = TVar::new(Box::new(0));
+    let b = TVar::new(Box::new(0));
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for x in 0..8 {
+        let (a, b) = (a.clone(), b.clone());
+        children.push(thread::spawn(move || {
+            for _ in 0..10000 {
+                if x % 2 == 0 {
+                    atomically(|trans| {
+                        let cur = try!(a.read(trans));
+                        try!(a.write(trans, Box::new(*cur + 1)));
+                        b.write(trans, Box::new(*cur + 1))
+                    });
+                } else {
+                    let (first, second) = atomically(|trans| {
+                        Ok((try!(a.read(trans)), try!(b.read(trans))))
+                    });
+                    assert_eq!(first, second);
+                }
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+    assert_eq!(*a.read_atomic(), 40000);
+}
+
+fn read_heavy_sum_stm() {
+    read_heavy_sum_transactional(|vars| {
+        atomically(|trans| {
+            let mut sum = 0;
+            for var in vars {
+                sum += try!(var.read(trans));
+            }
+            Ok(sum)
+        })
+    });
+}
+
+fn read_heavy_sum_stm_read_only() {
+    read_heavy_sum_transactional(|vars| {
+        atomically_read_only(|trans| {
+            let mut sum = 0;
+            for var in vars {
+                sum += try!(var.read(trans));
+            }
+            Ok(sum)
+        })
+    });
+}
+
+/// Sum up 64 vars over and over in 7 threads, while one thread moves
+/// amounts between them. `sum` is the reading transaction to measure.
+///
+/// Release build on a single core, median of three runs:
+///
+/// | backend    | `atomically` | `atomically_read_only` |
+/// |------------|--------------|------------------------|
+/// | Versioned  | 0.119 s      | 0.044 s                |
+/// | SeqLock    | 0.115 s      | 0.045 s                |
+/// | GlobalLock | 0.131 s      | 0.049 s                |
+///
+/// `read_heavy_sum_single_lock` takes 0.0007 s on the same machine,
+/// because without parallelism a single mutex is never contended.
+fn read_heavy_sum_transactional<F>(sum: F)
+where F: Fn(&[TVar<i32>]) -> i32 + Send + Sync + 'static
+{
+    let vars: Arc<Vec<TVar<i32>>> = Arc::new((0..64).map(|_| TVar::new(100)).collect());
+    let sum = Arc::new(sum);
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for x in 0..8 {
+        let vars = vars.clone();
+        let sum = sum.clone();
+        children.push(thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..2000 {
+                if x == 0 {
+                    let from = &vars[rng.gen_range(0, 64)];
+                    let to = &vars[rng.gen_range(0, 64)];
+                    atomically(|trans| {
+                        let amount = try!(from.read(trans));
+                        try!(from.write(trans, amount - 1));
+                        let amount = try!(to.read(trans));
+                        to.write(trans, amount + 1)
+                    });
+                } else {
+                    assert_eq!(sum(&vars), 6400);
+                }
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+}
+
+fn read_heavy_sum_single_lock() {
+    let l = Arc::new(Mutex::new(vec![100; 64]));
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for x in 0..8 {
+        let data = l.clone();
+        children.push(thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..2000 {
+                let mut vals = data.lock().unwrap();
+                if x == 0 {
+                    vals[rng.gen_range(0, 64)] -= 1;
+                    vals[rng.gen_range(0, 64)] += 1;
+                } else {
+                    assert_eq!(vals.iter().sum::<i32>(), 6400);
+                }
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+}
+
+/// Number of threads for the oversubscribed workloads.
+fn oversubscribed_threads() -> usize {
+    8 * thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Release build on a single core with 8 threads, median of three runs.
+/// "busy wait" replaces `wait::wait_for` with a loop, that only spins:
+///
+/// | backend    | workload    | adaptive wait | busy wait |
+/// |------------|-------------|---------------|-----------|
+/// | Versioned  | `_stm`      | 0.081 s       | 0.186 s   |
+/// | Versioned  | `_commute`  | 0.080 s       | 0.208 s   |
+/// | SeqLock    | `_stm`      | 0.069 s       | 0.179 s   |
+/// | SeqLock    | `_commute`  | 0.075 s       | 0.240 s   |
+/// | GlobalLock | `_stm`      | 0.108 s       | 0.122 s   |
+/// | GlobalLock | `_commute`  | 0.107 s       | 0.119 s   |
+///
+/// The global lock backend blocks on its mutex instead, so it hardly
+/// changes. `counter_oversubscribed_single_lock` takes 0.003 s.
+fn counter_oversubscribed_stm() {
+    // More threads than cores, so committers get descheduled
+    // while other threads wait for them.
+    let counter = TVar::new(0);
+    let threads = oversubscribed_threads();
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for _ in 0..threads {
+        let counter = counter.clone();
+        children.push(thread::spawn(move || {
+            for _ in 0..20000 {
+                atomically(|trans| {
+                    let cur = try!(counter.read(trans));
+                    counter.write(trans, cur + 1)
+                });
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+    assert_eq!(counter.read_atomic(), threads * 20000);
+}
+
+fn counter_oversubscribed_stm_commute() {
+    // The increments don't depend on each other, so they never conflict.
+    let counter = TVar::new(0);
+    let threads = oversubscribed_threads();
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for _ in 0..threads {
+        let counter = counter.clone();
+        children.push(thread::spawn(move || {
+            for _ in 0..20000 {
+                atomically(|trans| counter.commute(trans, |cur| *cur += 1));
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+    assert_eq!(counter.read_atomic(), threads * 20000);
+}
+
+fn counter_oversubscribed_single_lock() {
+    let l = Arc::new(Mutex::new(0));
+    let threads = oversubscribed_threads();
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for _ in 0..threads {
+        let data = l.clone();
+        children.push(thread::spawn(move || {
+            for _ in 0..20000 {
+                *data.lock().unwrap() += 1;
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+    assert_eq!(*l.lock().unwrap(), threads * 20000);
+}
+
+/// Node of a sorted list, that links its nodes through vars.
+struct TLlNode {
+    val: i32,
+    next: TVar<Option<Arc<TLlNode>>>,
+}
+
+/// Insert `new_val` into the sorted list starting at `head`, unless it is there already.
+fn tll_insert(head: &TVar<Option<Arc<TLlNode>>>, new_val: i32, trans: &mut Transaction) -> StmResult<()> {
+    let mut link = head.clone();
+    loop {
+        let next = match try!(link.read(trans)) {
+            Some(ref node) if node.val < new_val => node.next.clone(),
+            Some(ref node) if node.val == new_val => return Ok(()),
+            rest => {
+                let node = TLlNode { val: new_val, next: TVar::new(rest) };
+                return link.write(trans, Some(Arc::new(node)));
+            }
+        };
+        link = next;
+    }
+}
+
+fn tll_insertion_random_stm() {
+    tll_insertion_random_transactional(|head, val| atomically(|trans| tll_insert(head, val, trans)));
+}
+
+fn tll_insertion_random_stm_elastic() {
+    tll_insertion_random_transactional(|head, val| atomically_elastic(|trans| tll_insert(head, val, trans)));
+}
+
+#[test]
+fn elastic_list_insertion() {
+    test::each_backend(elastic_list_insertion_stm);
+}
+
+fn elastic_list_insertion_stm() {
+    // Inserts of all threads overlap, and some values are inserted twice.
+    // Released reads must never lose an insert or break the order.
+    let head = TVar::new(None);
+    let mut children = vec![];
+    for _ in 0..8 {
+        let head = head.clone();
+        children.push(thread::spawn(move || {
+            let mut rng = thread_rng();
+            let mut inserted = vec![];
+            for _ in 0..200 {
+                let val = rng.gen_range(0, 1000);
+                atomically_elastic(|trans| tll_insert(&head, val, trans));
+                inserted.push(val);
+            }
+            inserted
+        }));
+    }
+    let mut expected = vec![];
+    for child in children {
+        expected.extend(child.join().unwrap());
+    }
+    expected.sort();
+    expected.dedup();
+
+    let mut list = vec![];
+    let mut next = head.read_atomic();
+    while let Some(node) = next {
+        list.push(node.val);
+        next = node.next.read_atomic();
+    }
+    assert_eq!(list, expected);
+}
+
+/// Insert random values into a short list from 8 threads, so most inserts
+/// happen behind other running traversals.
+/// `insert` is the transaction to measure.
+fn tll_insertion_random_transactional<F>(insert: F)
+where F: Fn(&TVar<Option<Arc<TLlNode>>>, i32) + Send + Sync + 'static
+{
+    let head = TVar::new(None);
+    let mut rng = thread_rng();
+    for _ in 0..100 {
+        let val = rng.gen_range(0, 5000);
+        atomically(|trans| tll_insert(&head, val, trans));
+    }
+    let insert = Arc::new(insert);
+    let mut children = vec![];
+    let start = PreciseTime::now();
+    for _ in 0..8 {
+        let head = head.clone();
+        let insert = insert.clone();
+        children.push(thread::spawn(move || {
+            let mut rng = thread_rng();
+            for _ in 0..500 {
+                insert(&head, rng.gen_range(0, 5000));
+            }
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let end = PreciseTime::now();
+    println!("{} seconds for whatever you did.", start.to(end));
+
+    // The list is still sorted.
+    let mut link = head.read_atomic();
+    let mut last = -1;
+    while let Some(node) = link {
+        assert!(node.val > last);
+        last = node.val;
+        link = node.next.read_atomic();
+    }
+}
+
 #[derive(Clone)]
 #[derive(Debug)]
 struct LlNodeH {
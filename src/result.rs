@@ -20,3 +20,46 @@ pub enum StmError {
 /// For the later case, there is the `transaction.or(action1, action2)`, that
 /// is safe to use.
 pub type StmResult<T> = Result<T, StmError>;
+
+/// Error of `atomically_bounded`, if the transaction didn't commit
+/// within the given number of attempts.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Exhausted;
+
+/// Error of a transaction, that can be aborted by the user.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum TransactionError<E> {
+    /// The transaction has been aborted with a user error.
+    ///
+    /// All writes are discarded and the error is returned
+    /// from `atomically_result` without running the transaction again.
+    Abort(E),
+
+    /// A failure or a call to `retry`, that is handled by the STM.
+    Stm(StmError),
+}
+
+impl<E> From<StmError> for TransactionError<E> {
+    fn from(err: StmError) -> TransactionError<E> {
+        TransactionError::Stm(err)
+    }
+}
+
+/// Error of `atomically_checked`.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum CheckedError<E> {
+    /// The transaction has been aborted with `abort`.
+    Abort(E),
+
+    /// Committing would have violated the invariant with this name.
+    ///
+    /// The transaction has been aborted like with `abort`.
+    Violated(String),
+}
+
+/// TransactionResult is the result of a step of a STM calculation,
+/// that may abort with a user error of type `E`.
+///
+/// `StmResult`s can be converted with `try!`, so vars can be accessed
+/// the same way as in normal transactions.
+pub type TransactionResult<T, E> = Result<T, TransactionError<E>>;
@@ -1,7 +1,42 @@
 use std::thread;
 use std::time::Duration;
+use std::sync::{RwLock, RwLockReadGuard, PoisonError};
 use std::sync::mpsc::channel;
 
+use super::{Backend, set_backend};
+
+/// Held exclusively by tests, that change the backend, and shared by all other
+/// tests, that run transactions. Transactions of different backends don't
+/// synchronize with each other.
+static BACKEND_LOCK: RwLock<()> = RwLock::new(());
+
+/// Keep the backend from changing until the guard is dropped.
+pub fn shared_backend() -> RwLockReadGuard<'static, ()> {
+    BACKEND_LOCK.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Run `f` once with every backend.
+///
+/// No other test runs transactions meanwhile. The default backend
+/// is restored afterwards, even if `f` panics.
+pub fn each_backend<F>(f: F)
+where F: Fn(),
+{
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_backend(Backend::Versioned);
+        }
+    }
+
+    let _lock = BACKEND_LOCK.write().unwrap_or_else(PoisonError::into_inner);
+    let _restore = Restore;
+    for &backend in &[Backend::SeqLock, Backend::Versioned, Backend::GlobalLock] {
+        set_backend(backend);
+        f();
+    }
+}
+
 /// Check if a function `f` terminates within a given timeframe.
 ///
 /// It is used to check for deadlocks.
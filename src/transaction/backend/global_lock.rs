@@ -0,0 +1,109 @@
+use std::sync::{Mutex, Condvar, PoisonError};
+use std::sync::atomic::Ordering;
+use std::mem;
+use std::time::Instant;
+
+use super::{StmBackend, GLOBAL_CLOCK, oldest_snapshot, publish};
+use super::super::Transaction;
+use super::super::wait;
+use super::super::super::variable::Var;
+use super::super::super::result::*;
+
+/// A lock, that is held from `begin` until `commit` or `abort`.
+///
+/// It can't be a `MutexGuard`, because it has to outlive the
+/// call, that acquires it.
+struct GlobalMutex {
+    locked: Mutex<bool>,
+    released: Condvar,
+}
+
+impl GlobalMutex {
+    /// Wait for the lock. Returns `false`, if `deadline` passes first.
+    fn acquire(&self, deadline: Option<Instant>) -> bool {
+        let mut locked = self.locked.lock().unwrap_or_else(PoisonError::into_inner);
+        while *locked {
+            locked = match deadline {
+                None => self.released.wait(locked).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.released.wait_timeout(locked, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+        *locked = true;
+        true
+    }
+
+    fn release(&self) {
+        let mut locked = self.locked.lock().unwrap_or_else(PoisonError::into_inner);
+        *locked = false;
+        self.released.notify_one();
+    }
+}
+
+static GLOBAL_MUTEX: GlobalMutex = GlobalMutex {
+    locked: Mutex::new(false),
+    released: Condvar::new(),
+};
+
+/// A running commit.
+///
+/// The clock is odd until the guard is dropped. Dropping it also releases
+/// the global mutex, so a panic during the commit doesn't block others forever.
+struct CommitGuard {
+    version: usize,
+}
+
+impl Drop for CommitGuard {
+    fn drop(&mut self) {
+        GLOBAL_CLOCK.store(self.version, Ordering::SeqCst);
+        wait::commit_finished();
+        GLOBAL_MUTEX.release();
+    }
+}
+
+/// Backend, that runs one transaction at a time.
+pub struct GlobalLock;
+
+impl StmBackend for GlobalLock {
+    fn begin(&self, deadline: Option<Instant>) -> Option<usize> {
+        if GLOBAL_MUTEX.acquire(deadline) { Some(0) } else { None }
+    }
+
+    fn read(&self, _trans: &mut Transaction, var: &Var, load: &mut FnMut()) -> StmResult<usize> {
+        load();
+        Ok(var.version())
+    }
+
+    fn validate(&self, trans: &Transaction) -> Option<usize> {
+        // Nobody else can commit, while the lock is held.
+        Some(trans.snapshot)
+    }
+
+    fn commit(&self, trans: &mut Transaction) -> bool {
+        // Read-only transactions don't take the lock. The clock is odd
+        // during the commit, so that none of them starts in the middle.
+        let version = GLOBAL_CLOCK.fetch_add(1, Ordering::SeqCst) + 2;
+        let guard = CommitGuard { version: version };
+
+        // Commutes may panic. Run them before anything is published.
+        let values: Vec<_> = trans.vars.iter()
+            .filter_map(|(var, value)| value.commit_value(&**var).map(|value| (var, value)))
+            .collect();
+        let oldest = oldest_snapshot();
+        for (var, value) in values {
+            publish(var, value, version, oldest);
+        }
+        mem::drop(guard);
+        true
+    }
+
+    fn abort(&self, _trans: &mut Transaction) {
+        GLOBAL_MUTEX.release();
+    }
+}
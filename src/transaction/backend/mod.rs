@@ -0,0 +1,351 @@
+//! Algorithms, that synchronize transactions.
+//!
+//! All backends buffer writes in the log of the transaction. They differ in
+//! how reads are kept consistent and how the log is published on commit.
+
+mod seq_lock;
+mod versioned;
+mod global_lock;
+
+use std::sync::{Arc, Weak, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, PoisonError};
+use std::sync::{TryLockError, TryLockResult};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::collections::BTreeMap;
+use std::mem;
+use std::cmp;
+use std::time::{Duration, Instant};
+use std::thread;
+
+use super::Transaction;
+use super::wait;
+use super::log_var::ArcAny;
+use super::super::variable::Var;
+use super::super::result::*;
+
+/// The algorithm used to run transactions.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Backend {
+    /// All commits are serialized by a global sequence lock.
+    ///
+    /// Reads are validated by value, whenever another transaction commits.
+    SeqLock,
+
+    /// Every var has its own versioned lock.
+    ///
+    /// Commits only lock the vars they write, so transactions
+    /// on disjoint vars commit in parallel.
+    Versioned,
+
+    /// A single global mutex is held for the whole transaction.
+    ///
+    /// Transactions never conflict, but they don't run in parallel either.
+    GlobalLock,
+}
+
+/// Global clock, that is shared by all backends.
+///
+/// Every writing commit advances it by two and stamps the new value on the
+/// written vars, so versions only grow, even when switching the backend.
+/// The lowest bit stays free for `LOCKED` and the sequence lock.
+static GLOBAL_CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshots of the running read-only and snapshot isolated transactions.
+///
+/// Maps each snapshot to the number of transactions, that read from it.
+static ACTIVE_SNAPSHOTS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Number of pinned snapshots.
+///
+/// Lets commits skip `ACTIVE_SNAPSHOTS`, while there are none.
+static PINNED: AtomicUsize = AtomicUsize::new(0);
+
+/// Vars, that keep old values for pinned snapshots. See `publish`.
+static RETAINING: Mutex<Vec<Weak<Var>>> = Mutex::new(Vec::new());
+
+/// Held exclusively by an irrevocable transaction.
+///
+/// Writing commits only take it shared, while `COMMITS_BLOCKED` is set.
+static COMMIT_GATE: RwLock<()> = RwLock::new(());
+
+/// Set while a transaction holds `COMMIT_GATE` exclusively.
+static COMMITS_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Number of stripes of `RUNNING_COMMITS`.
+const COMMIT_STRIPES: usize = 16;
+
+/// A counter on its own cache line.
+#[repr(align(64))]
+struct Stripe(AtomicUsize);
+
+/// Writing commits, that passed the gate without taking it.
+///
+/// Every thread counts its commits in one of the stripes, so that
+/// commits of different threads don't write to the same cache line.
+static RUNNING_COMMITS: [Stripe; COMMIT_STRIPES] = [const { Stripe(AtomicUsize::new(0)) }; COMMIT_STRIPES];
+
+/// Source of the stripe of the next thread.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Stripe of `RUNNING_COMMITS`, that this thread counts its commits in.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % COMMIT_STRIPES;
+}
+
+static BACKEND: AtomicUsize = AtomicUsize::new(Backend::Versioned as usize);
+
+/// Select the algorithm for all transactions started afterwards.
+///
+/// Transactions of different backends don't synchronize with each other,
+/// so this should be called at startup, while no transaction is running.
+pub fn set_backend(backend: Backend) {
+    BACKEND.store(backend as usize, Ordering::SeqCst);
+}
+
+/// Get the currently selected algorithm.
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::SeqCst) {
+        x if x == Backend::SeqLock as usize => Backend::SeqLock,
+        x if x == Backend::Versioned as usize => Backend::Versioned,
+        _ => Backend::GlobalLock,
+    }
+}
+
+/// Get the implementation of the currently selected algorithm.
+pub fn current() -> &'static StmBackend {
+    static SEQ_LOCK: seq_lock::SeqLock = seq_lock::SeqLock;
+    static VERSIONED: versioned::Versioned = versioned::Versioned;
+    static GLOBAL_LOCK: global_lock::GlobalLock = global_lock::GlobalLock;
+
+    match backend() {
+        Backend::SeqLock => &SEQ_LOCK,
+        Backend::Versioned => &VERSIONED,
+        Backend::GlobalLock => &GLOBAL_LOCK,
+    }
+}
+
+/// Start a read-only transaction at the current point in time.
+///
+/// The snapshot is registered, so that commits keep the values it can
+/// see in the history of the vars. Release it with `unpin_snapshot`.
+pub fn pin_snapshot() -> usize {
+    loop {
+        // Don't wait for a running commit while holding the lock,
+        // because the commit needs it for `oldest_snapshot`.
+        wait::wait_for(|| {
+            if GLOBAL_CLOCK.load(Ordering::SeqCst) & 1 == 0 { Some(()) } else { None }
+        });
+
+        let mut active = ACTIVE_SNAPSHOTS.lock().unwrap_or_else(PoisonError::into_inner);
+        // Announce the snapshot before reading the clock. A commit, that
+        // doesn't see the announcement, has already advanced the clock.
+        PINNED.fetch_add(1, Ordering::SeqCst);
+        let snapshot = GLOBAL_CLOCK.load(Ordering::SeqCst);
+        if snapshot & 1 == 0 {
+            *active.entry(snapshot).or_insert(0) += 1;
+            return snapshot;
+        }
+        PINNED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Get the current value of the global clock.
+pub fn clock() -> usize {
+    GLOBAL_CLOCK.load(Ordering::SeqCst)
+}
+
+/// Release a snapshot taken by `pin_snapshot`.
+pub fn unpin_snapshot(snapshot: usize) {
+    let mut active = ACTIVE_SNAPSHOTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let last = match active.get_mut(&snapshot) {
+        Some(count) => {
+            *count -= 1;
+            *count == 0
+        }
+        None => unreachable!(),
+    };
+    // Old values are only dropped on commit. Without the oldest
+    // snapshot, some of them may not be needed anymore.
+    let oldest = last && active.keys().next() == Some(&snapshot);
+    if last {
+        active.remove(&snapshot);
+    }
+    PINNED.fetch_sub(1, Ordering::SeqCst);
+    mem::drop(active);
+    if oldest {
+        prune_histories();
+    }
+}
+
+/// Publish a value of a commit. See `VarControlBlock::publish`.
+///
+/// Vars, that start to keep old values, are remembered, so that
+/// the values can be dropped, once no snapshot needs them anymore.
+pub fn publish(var: &Arc<Var>, value: ArcAny, version: usize, oldest_snapshot: Option<usize>) {
+    if var.publish_any(value, version, oldest_snapshot) {
+        let mut retaining = RETAINING.lock().unwrap_or_else(PoisonError::into_inner);
+        retaining.push(Arc::downgrade(var));
+    }
+}
+
+/// Drop old values of all vars, that no pinned snapshot can see anymore.
+fn prune_histories() {
+    let retaining = {
+        let mut retaining = RETAINING.lock().unwrap_or_else(PoisonError::into_inner);
+        mem::replace(&mut *retaining, Vec::new())
+    };
+    if retaining.is_empty() {
+        return;
+    }
+    // A var may have been added again, after its history had become empty.
+    let mut vars: Vec<_> = retaining.iter().filter_map(Weak::upgrade).collect();
+    vars.sort();
+    vars.dedup();
+    let kept = vars.iter().filter(|var| var.prune_history()).map(Arc::downgrade);
+
+    let mut retaining = RETAINING.lock().unwrap_or_else(PoisonError::into_inner);
+    retaining.extend(kept);
+}
+
+/// Get the oldest snapshot, that a read-only transaction reads from.
+///
+/// A commit must call this after it has advanced the global clock or made
+/// it odd, so that it can't miss a transaction, that starts before its writes.
+pub fn oldest_snapshot() -> Option<usize> {
+    if PINNED.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    let active = ACTIVE_SNAPSHOTS.lock().unwrap_or_else(PoisonError::into_inner);
+    active.keys().next().cloned()
+}
+
+/// Longest time between two attempts to take the commit gate before a deadline.
+const GATE_POLL: Duration = Duration::from_millis(1);
+
+/// Lets a writing commit proceed, until it is dropped.
+pub struct CommitPermit {
+    /// Stripe, that counts the commit, if the gate hasn't been taken.
+    counted: Option<&'static AtomicUsize>,
+
+    _gate: Option<RwLockReadGuard<'static, ()>>,
+}
+
+impl Drop for CommitPermit {
+    fn drop(&mut self) {
+        if let Some(counter) = self.counted {
+            counter.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Keeps other transactions from committing writes, until it is dropped.
+pub struct CommitsBlocked {
+    _gate: RwLockWriteGuard<'static, ()>,
+}
+
+impl Drop for CommitsBlocked {
+    fn drop(&mut self) {
+        // Runs before the gate is released.
+        COMMITS_BLOCKED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Allow a writing commit to proceed.
+///
+/// Blocks while an irrevocable transaction runs. Returns `None`,
+/// if `deadline` passes first.
+///
+/// Otherwise the commit is only counted, so commits don't contend
+/// on a shared lock, while no transaction is irrevocable.
+pub fn commit_permit(deadline: Option<Instant>) -> Option<CommitPermit> {
+    let counter = &RUNNING_COMMITS[STRIPE.with(|stripe| *stripe)].0;
+    // Count the commit before checking the flag. `block_commits` sets
+    // the flag before checking the counters, so one of them sees the other.
+    counter.fetch_add(1, Ordering::SeqCst);
+    if !COMMITS_BLOCKED.load(Ordering::SeqCst) {
+        return Some(CommitPermit { counted: Some(counter), _gate: None });
+    }
+    counter.fetch_sub(1, Ordering::SeqCst);
+
+    let gate = match deadline {
+        None => COMMIT_GATE.read().unwrap_or_else(PoisonError::into_inner),
+        Some(deadline) => take_gate(deadline, || COMMIT_GATE.try_read())?,
+    };
+    Some(CommitPermit { counted: None, _gate: Some(gate) })
+}
+
+/// Stop all other transactions from committing writes.
+///
+/// Waits for running commits to finish. The guard is held by the
+/// irrevocable transaction until it has committed itself. Returns
+/// `None`, if `deadline` passes first.
+pub fn block_commits(deadline: Option<Instant>) -> Option<CommitsBlocked> {
+    let gate = match deadline {
+        None => COMMIT_GATE.write().unwrap_or_else(PoisonError::into_inner),
+        Some(deadline) => take_gate(deadline, || COMMIT_GATE.try_write())?,
+    };
+    COMMITS_BLOCKED.store(true, Ordering::SeqCst);
+    let blocked = CommitsBlocked { _gate: gate };
+
+    // Commits, that have been counted before the flag was set, are short.
+    while RUNNING_COMMITS.iter().any(|stripe| stripe.0.load(Ordering::SeqCst) != 0) {
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        thread::yield_now();
+    }
+    Some(blocked)
+}
+
+/// Poll `try_lock` until it succeeds or `deadline` has passed.
+///
+/// The gate is only held for the length of a commit or of an irrevocable
+/// transaction, so polling doesn't cost much compared to the wait.
+fn take_gate<G, F>(deadline: Instant, try_lock: F) -> Option<G>
+where F: Fn() -> TryLockResult<G>,
+{
+    loop {
+        match try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(err)) => return Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        thread::sleep(cmp::min(deadline - now, GATE_POLL));
+    }
+}
+
+/// Implementation of an algorithm.
+///
+/// An attempt of a transaction starts with `begin` and ends with either
+/// `commit` or `abort`. Writes don't go through the backend, they are
+/// collected in the log of the transaction until `commit`.
+pub trait StmBackend: Sync {
+    /// Start an attempt and return the snapshot, that reads are consistent with.
+    ///
+    /// Returns `None`, if the attempt couldn't start before `deadline`.
+    fn begin(&self, deadline: Option<Instant>) -> Option<usize>;
+
+    /// Read the last committed value of `var` and return its version.
+    ///
+    /// The value is taken by calling `load`, that copies it with its type
+    /// from the var, and may be called more than once. The last value taken
+    /// must be consistent with all other reads of the transaction.
+    /// The snapshot of the transaction may be moved forward to achieve that.
+    fn read(&self, trans: &mut Transaction, var: &Var, load: &mut FnMut()) -> StmResult<usize>;
+
+    /// Check that all reads of the transaction are still valid.
+    ///
+    /// Returns the new snapshot, that all reads are consistent with.
+    fn validate(&self, trans: &Transaction) -> Option<usize>;
+
+    /// Publish all writes of the transaction and end the attempt.
+    ///
+    /// Returns `false`, if the transaction has to be run again.
+    fn commit(&self, trans: &mut Transaction) -> bool;
+
+    /// End an attempt, that didn't commit.
+    fn abort(&self, _trans: &mut Transaction) { }
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::Ordering;
+use std::mem;
+use std::time::Instant;
+
+use super::{StmBackend, oldest_snapshot, publish};
+use super::GLOBAL_CLOCK as GLOBAL_SEQ_LOCK;
+use super::super::Transaction;
+use super::super::wait;
+use super::super::super::variable::Var;
+use super::super::super::result::*;
+use super::super::super::result::StmError::*;
+
+/// Releases the global sequence lock at the end of a commit.
+///
+/// This happens in `drop`, so the lock is released even if the commit
+/// panics. Otherwise every other thread would wait for it forever.
+struct SeqLockGuard {
+    next: usize,
+}
+
+impl Drop for SeqLockGuard {
+    fn drop(&mut self) {
+        GLOBAL_SEQ_LOCK.store(self.next, Ordering::SeqCst);
+        wait::commit_finished();
+    }
+}
+
+/// Wait until no transaction commits and return the sequence lock.
+fn wait_even() -> usize {
+    wait::wait_for(|| {
+        let ss = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
+        if ss & 1 == 0 { Some(ss) } else { None }
+    })
+}
+
+/// Load the value of a var and get its version.
+///
+/// Both belong together, if the sequence lock didn't change in between.
+fn read_versioned(var: &Var, load: &mut FnMut()) -> usize {
+    let version = var.version();
+    load();
+    version
+}
+
+/// Backend with a global sequence lock (NOrec).
+///
+/// The global clock serves as sequence lock. It is odd
+/// while a transaction commits and even otherwise.
+pub struct SeqLock;
+
+impl StmBackend for SeqLock {
+    fn begin(&self, _deadline: Option<Instant>) -> Option<usize> {
+        // Commits are short, so they are always waited for.
+        Some(wait_even())
+    }
+
+    fn read(&self, trans: &mut Transaction, var: &Var, load: &mut FnMut()) -> StmResult<usize> {
+        let mut version = read_versioned(var, load);
+        while trans.snapshot != GLOBAL_SEQ_LOCK.load(Ordering::SeqCst) {
+            match self.validate(trans) {
+                None => { return Err(Failure); }
+                Some(ss) => {
+                    trans.snapshot = ss;
+                    version = read_versioned(var, load);
+                }
+            }
+        }
+        Ok(version)
+    }
+
+    fn validate(&self, trans: &Transaction) -> Option<usize> {
+        loop {
+            let time = wait_even();
+            for (var, value) in &trans.vars {
+                if let Some(original) = value.dependency() {
+                    if var.version() != original.version {
+                        return None;
+                    }
+                }
+            }
+            if time == GLOBAL_SEQ_LOCK.load(Ordering::SeqCst) {
+                return Some(time);
+            }
+        }
+    }
+
+    fn commit(&self, trans: &mut Transaction) -> bool {
+        if trans.vars.values().all(|v| !v.writes()) {
+            return true;
+        }
+        while GLOBAL_SEQ_LOCK.compare_exchange(trans.snapshot, trans.snapshot + 1,
+                                               Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            match self.validate(trans) {
+                None => { return false; }
+                Some(ss) => { trans.snapshot = ss; }
+            }
+        }
+        let version = trans.snapshot + 2;
+        let seq_lock = SeqLockGuard { next: version };
+
+        // Commutes may panic. Run them before anything is published.
+        let values: Vec<_> = trans.vars.iter()
+            .filter_map(|(var, value)| value.commit_value(&**var).map(|value| (var, value)))
+            .collect();
+        let oldest = oldest_snapshot();
+        for (var, value) in values {
+            publish(var, value, version, oldest);
+        }
+        mem::drop(seq_lock);
+        true
+    }
+}
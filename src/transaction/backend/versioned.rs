@@ -0,0 +1,133 @@
+use std::sync::atomic::Ordering;
+use std::mem;
+use std::time::Instant;
+
+use super::{StmBackend, GLOBAL_CLOCK, oldest_snapshot, publish};
+use super::super::Transaction;
+use super::super::wait;
+use super::super::super::variable::{Var, LOCKED};
+use super::super::super::result::*;
+use super::super::super::result::StmError::*;
+
+/// Versioned locks of the vars, that a transaction is committing to.
+///
+/// They are released in `drop`, so that a panic during the commit
+/// can't leave vars locked forever.
+struct WriteLocks<'a> {
+    /// Locked vars with their version before the commit.
+    locked: Vec<(&'a Var, usize)>,
+
+    /// Number of vars at the front of `locked`, that got their new value.
+    ///
+    /// Publishing the value releases the lock with the new version.
+    published: usize,
+}
+
+impl<'a> Drop for WriteLocks<'a> {
+    fn drop(&mut self) {
+        for &(var, old) in &self.locked[self.published..] {
+            var.set_version(old);
+        }
+        wait::commit_finished();
+    }
+}
+
+/// Load the value of a var, that is not being committed to, and get its version.
+fn read_stable(var: &Var, load: &mut FnMut()) -> usize {
+    loop {
+        // Wait for the commit to finish.
+        let version = wait::wait_for(|| {
+            let version = var.version();
+            if version & LOCKED == 0 { Some(version) } else { None }
+        });
+        load();
+        if var.version() == version {
+            return version;
+        }
+    }
+}
+
+/// Backend with a versioned lock per var (TL2).
+pub struct Versioned;
+
+impl StmBackend for Versioned {
+    fn begin(&self, _deadline: Option<Instant>) -> Option<usize> {
+        Some(GLOBAL_CLOCK.load(Ordering::SeqCst))
+    }
+
+    fn read(&self, trans: &mut Transaction, var: &Var, load: &mut FnMut()) -> StmResult<usize> {
+        let version = read_stable(var, load);
+        if version > trans.snapshot {
+            // Move the snapshot forward, if all reads are still valid.
+            match self.validate(trans) {
+                None => { return Err(Failure); }
+                Some(ss) => { trans.snapshot = ss; }
+            }
+        }
+        Ok(version)
+    }
+
+    fn validate(&self, trans: &Transaction) -> Option<usize> {
+        let time = GLOBAL_CLOCK.load(Ordering::SeqCst);
+        for (var, value) in &trans.vars {
+            if value.dependency().is_some() {
+                let version = var.version();
+                if version & LOCKED != 0 || version > trans.snapshot {
+                    return None;
+                }
+            }
+        }
+        Some(time)
+    }
+
+    fn commit(&self, trans: &mut Transaction) -> bool {
+        let mut writes: Vec<_> = trans.vars.iter()
+            .filter(|&(_, value)| value.writes())
+            .collect();
+        if writes.is_empty() {
+            return true;
+        }
+
+        // Lock the written vars in the order of their addresses,
+        // so concurrent commits can't deadlock.
+        writes.sort_by(|a, b| a.0.cmp(b.0));
+        let mut locks = WriteLocks {
+            locked: Vec::with_capacity(writes.len()),
+            published: 0,
+        };
+        for &(var, log) in &writes {
+            let version = var.lock_version();
+            locks.locked.push((&**var, version));
+            if log.dependency().is_some() && version > trans.snapshot {
+                return false;
+            }
+        }
+
+        let version = GLOBAL_CLOCK.fetch_add(2, Ordering::SeqCst) + 2;
+
+        // Nobody else has committed since the snapshot, if the clock
+        // advanced only by this commit. Otherwise check the other reads.
+        if version != trans.snapshot + 2 {
+            for (var, value) in &trans.vars {
+                if value.dependency().is_some() && !value.writes() {
+                    let current = var.version();
+                    if current & LOCKED != 0 || current > trans.snapshot {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        // Commutes may panic. Run them before anything is published.
+        let values: Vec<_> = writes.iter()
+            .map(|&(var, log)| log.commit_value(&**var).expect("written var without value"))
+            .collect();
+        let oldest = oldest_snapshot();
+        for (&(var, _), value) in writes.iter().zip(values) {
+            publish(var, value, version, oldest);
+            locks.published += 1;
+        }
+        mem::drop(locks);
+        true
+    }
+}
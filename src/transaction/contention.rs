@@ -0,0 +1,226 @@
+//! Policies, that decide how long a transaction waits after a conflict.
+//!
+//! Without waiting, transactions that conflict with each other keep
+//! restarting at the same time and conflict again. Transactions, that
+//! conflict too often, are serialized as a last resort.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+
+/// The built-in contention managers.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ContentionPolicy {
+    /// Run again immediately.
+    None,
+
+    /// Wait for a random time, that doubles with every failed attempt.
+    Backoff,
+
+    /// Let the transaction, that has done the most work, go first.
+    ///
+    /// Work done in failed attempts accumulates as priority. Transactions
+    /// with a lower priority than the highest one back off exponentially.
+    Polka,
+}
+
+impl ContentionPolicy {
+    /// Create a contention manager for a single transaction.
+    pub fn manager(self) -> Box<ContentionManager> {
+        match self {
+            ContentionPolicy::None => Box::new(NoBackoff),
+            ContentionPolicy::Backoff => Box::new(Backoff),
+            ContentionPolicy::Polka => Box::new(Polka::new()),
+        }
+    }
+}
+
+static POLICY: AtomicUsize = AtomicUsize::new(ContentionPolicy::None as usize);
+
+/// Select the contention manager for all transactions started afterwards.
+pub fn set_contention_policy(policy: ContentionPolicy) {
+    POLICY.store(policy as usize, Ordering::SeqCst);
+}
+
+/// Get the currently selected contention manager.
+pub fn contention_policy() -> ContentionPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        x if x == ContentionPolicy::None as usize => ContentionPolicy::None,
+        x if x == ContentionPolicy::Backoff as usize => ContentionPolicy::Backoff,
+        _ => ContentionPolicy::Polka,
+    }
+}
+
+/// Number of conflicts, after which a transaction runs serialized. Zero for never.
+static SERIALIZE_AFTER: AtomicUsize = AtomicUsize::new(32);
+
+/// Set the number of conflicts, after which a transaction runs serialized.
+///
+/// A serialized attempt stops all other transactions from committing
+/// writes, so it is guaranteed to commit. This keeps long transactions
+/// from being starved by short ones. `None` turns it off.
+pub fn set_serialize_after(conflicts: Option<usize>) {
+    SERIALIZE_AFTER.store(conflicts.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// Get the number of conflicts, after which a transaction runs serialized.
+pub fn serialize_after() -> Option<usize> {
+    match SERIALIZE_AFTER.load(Ordering::SeqCst) {
+        0 => None,
+        x => Some(x),
+    }
+}
+
+/// Decides what a transaction does after a conflict.
+///
+/// A manager belongs to a single run of a transaction,
+/// so it can keep state across the attempts.
+pub trait ContentionManager {
+    /// Called after an attempt failed because of a conflict.
+    ///
+    /// The transaction runs again, once this returns. `attempts` is the
+    /// number of attempts so far and `work` the number of vars, that the
+    /// failed attempt has accessed.
+    fn conflict(&mut self, attempts: usize, work: usize);
+
+    /// Called once the transaction has committed or has been aborted.
+    fn finished(&mut self) { }
+}
+
+/// Longest backoff is `2^MAX_EXPONENT` microseconds.
+const MAX_EXPONENT: usize = 10;
+
+/// Wait for a random time of up to `2^attempts` microseconds.
+fn backoff(attempts: usize) {
+    let limit = 1u64 << cmp::min(attempts, MAX_EXPONENT);
+    let micros = thread_rng().gen_range(0, limit + 1);
+    if micros == 0 {
+        thread::yield_now();
+    } else {
+        thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+/// Manager of `ContentionPolicy::None`.
+pub struct NoBackoff;
+
+impl ContentionManager for NoBackoff {
+    fn conflict(&mut self, _attempts: usize, _work: usize) { }
+}
+
+/// Manager of `ContentionPolicy::Backoff`.
+pub struct Backoff;
+
+impl ContentionManager for Backoff {
+    fn conflict(&mut self, attempts: usize, _work: usize) {
+        backoff(attempts);
+    }
+}
+
+/// Highest priority of all running `Polka` transactions.
+static TOP_KARMA: AtomicUsize = AtomicUsize::new(0);
+
+/// Manager of `ContentionPolicy::Polka`.
+pub struct Polka {
+    /// Number of vars accessed by all failed attempts.
+    karma: usize,
+
+    /// Number of times this transaction backed off.
+    backoffs: usize,
+}
+
+impl Polka {
+    pub fn new() -> Polka {
+        Polka {
+            karma: 0,
+            backoffs: 0,
+        }
+    }
+}
+
+impl ContentionManager for Polka {
+    fn conflict(&mut self, _attempts: usize, work: usize) {
+        self.karma += work;
+        let top = cmp::max(TOP_KARMA.fetch_max(self.karma, Ordering::SeqCst), self.karma);
+        if self.karma < top {
+            self.backoffs += 1;
+            backoff(self.backoffs);
+        }
+    }
+
+    fn finished(&mut self) {
+        // Hand the top priority on to the others.
+        let _ = TOP_KARMA.compare_exchange(self.karma, 0, Ordering::SeqCst, Ordering::SeqCst);
+        // The manager may be reused for the next transaction.
+        self.karma = 0;
+        self.backoffs = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Mutex, PoisonError};
+    use std::time::Instant;
+
+    use super::*;
+    use super::super::super::test::shared_backend;
+    use super::super::super::{TVar, atomically_with};
+
+    /// Held by tests, that depend on `TOP_KARMA` or change the policy.
+    static KARMA_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn select_policy() {
+        // Other transactions would use the policies for a moment.
+        let _karma = KARMA_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        for &policy in &[ContentionPolicy::Backoff, ContentionPolicy::Polka, ContentionPolicy::None] {
+            set_contention_policy(policy);
+            assert_eq!(contention_policy(), policy);
+        }
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let mut manager = Backoff;
+        let start = Instant::now();
+        for attempts in 0..100 {
+            manager.conflict(attempts, 1);
+        }
+        // At most 2^MAX_EXPONENT microseconds each, plus the cost of sleeping.
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn polka_backs_off_for_more_work() {
+        let _karma = KARMA_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut high = Polka::new();
+        let mut low = Polka::new();
+        high.conflict(1, 1000);
+        assert_eq!(high.backoffs, 0);
+        low.conflict(1, 1);
+        assert_eq!(low.backoffs, 1);
+
+        // Finishing hands the priority on and resets the manager.
+        high.finished();
+        assert_eq!((high.karma, high.backoffs), (0, 0));
+        low.conflict(2, 1);
+        assert_eq!(low.backoffs, 1);
+        low.finished();
+    }
+
+    #[test]
+    fn polka_is_reset_between_transactions() {
+        let _karma = KARMA_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let mut manager = Polka::new();
+        manager.conflict(1, 5);
+        for _ in 0..3 {
+            atomically_with(&mut manager, |trans| var.modify(trans, |x| *x += 1));
+            assert_eq!((manager.karma, manager.backoffs), (0, 0));
+        }
+        assert_eq!(var.read_atomic(), 3);
+    }
+}
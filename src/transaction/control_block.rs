@@ -6,8 +6,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::sync::{Mutex, Condvar};
+use std::sync::{Mutex, Condvar, PoisonError};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 #[cfg(test)]
 use super::super::test::{terminates, terminates_async};
@@ -51,6 +52,9 @@ impl ControlBlock {
     pub fn set_changed(&self) {
         // unblock
         self.blocked.store(false, Ordering::SeqCst);
+        // Hold the lock while notifying, otherwise the wakeup could get lost
+        // between the check of `blocked` and the call to `wait_cvar.wait`.
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
         // wake thread
         self.wait_cvar.notify_one();
     }
@@ -61,11 +65,51 @@ impl ControlBlock {
     ///
     /// `wait` needs to be called by the STM instance itself.
     pub fn wait(&self) {
-        let mut blocked = self.blocked.load(Ordering::SeqCst);
-        let mut lock = self.lock.lock().unwrap();
-        while blocked {
-            lock = self.wait_cvar.wait(lock).unwrap();
-            blocked = self.blocked.load(Ordering::SeqCst);
+        let mut lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.blocked.load(Ordering::SeqCst) {
+            lock = self.wait_cvar.wait(lock).unwrap_or_else(PoisonError::into_inner);
         }
     }
-}
\ No newline at end of file
+
+    /// Block until one variable has changed or `deadline` has passed.
+    ///
+    /// Returns `true` if a variable has changed.
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let mut lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.blocked.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            lock = self.wait_cvar.wait_timeout(lock, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use super::*;
+
+    #[test]
+    fn wait_after_change() {
+        let ctrl = ControlBlock::new();
+        ctrl.set_changed();
+        assert!(terminates(50, move || ctrl.wait()));
+    }
+
+    #[test]
+    fn wait_until_change() {
+        let ctrl = Arc::new(ControlBlock::new());
+        let ctrl2 = ctrl.clone();
+        assert!(terminates_async(500, move || ctrl2.wait(), || ctrl.set_changed()));
+    }
+
+    #[test]
+    fn wait_without_change() {
+        let ctrl = ControlBlock::new();
+        assert!(!terminates(100, move || ctrl.wait()));
+    }
+}
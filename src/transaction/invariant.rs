@@ -0,0 +1,158 @@
+use std::collections::{BTreeSet, BTreeMap};
+use std::sync::{Arc, Weak, Mutex, OnceLock, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Transaction;
+use super::super::variable::{TVar, Var};
+use super::super::result::*;
+
+/// A predicate over vars, that must hold after every commit.
+///
+/// Invariants are registered with `Transaction::always` and checked by every
+/// committing transaction, that writes to one of their dependencies.
+pub struct Invariant {
+    name: String,
+
+    check: Box<Fn(&mut Transaction) -> StmResult<bool> + Send + Sync>,
+
+    /// Vars, that have been read while checking the invariant, by address.
+    ///
+    /// Invariants are never removed, so they must not keep the vars alive.
+    /// A `Weak` keeps the address from being reused, until dropped vars
+    /// are removed. Otherwise the set only grows, so that a concurrent
+    /// check can't remove a dependency, that another one has just found.
+    dependencies: Mutex<BTreeMap<usize, Weak<Var>>>,
+}
+
+impl Invariant {
+    pub fn new<F>(name: &str, check: F) -> Invariant
+        where F: Fn(&mut Transaction) -> StmResult<bool> + Send + Sync + 'static
+    {
+        Invariant {
+            name: name.to_owned(),
+            check: Box::new(check),
+            dependencies: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Evaluate the invariant inside of `transaction`.
+    pub fn check(&self, transaction: &mut Transaction) -> StmResult<bool> {
+        (self.check)(transaction)
+    }
+
+    /// Check if writing to `written` may break the invariant.
+    ///
+    /// An invariant without known dependencies has never been
+    /// checked before, so it is always affected.
+    pub fn affected_by(&self, written: &[Arc<Var>]) -> bool {
+        let deps = self.dependencies.lock().unwrap_or_else(PoisonError::into_inner);
+        deps.is_empty() || written.iter().any(|var| deps.contains_key(&var.address()))
+    }
+
+    /// Add vars, that have been read during a check.
+    pub fn add_dependencies(&self, vars: BTreeSet<Arc<Var>>) {
+        let mut deps = self.dependencies.lock().unwrap_or_else(PoisonError::into_inner);
+        deps.retain(|_, var| var.strong_count() != 0);
+        deps.extend(vars.iter().map(|var| (var.address(), Arc::downgrade(var))));
+    }
+}
+
+/// Number of calls to `Transaction::always`.
+///
+/// Registrations of aborted transactions are counted as well, so
+/// this is only zero, if no invariant has ever been registered.
+static REGISTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Count a registration. Must be called before the registry is written.
+pub fn add_registered() {
+    REGISTERED.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Check if an invariant may have been registered.
+pub fn any_registered() -> bool {
+    REGISTERED.load(Ordering::SeqCst) != 0
+}
+
+/// All registered invariants.
+///
+/// The registry is a var itself, so that registering an invariant
+/// conflicts with every transaction, that commits without checking it.
+pub fn registry() -> &'static TVar<Vec<Arc<Invariant>>> {
+    static REGISTRY: OnceLock<TVar<Vec<Arc<Invariant>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| TVar::new(Vec::new()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::super::super::test::shared_backend;
+    use super::super::super::{TVar, atomically, atomically_checked, CheckedError};
+
+    #[test]
+    fn violation_aborts_with_error() {
+        let _backend = shared_backend();
+        let var = TVar::new(5);
+        let checked = var.clone();
+        atomically(|trans| {
+            let checked = checked.clone();
+            trans.always("non-negative", move |trans| Ok(checked.read(trans)? >= 0))
+        });
+
+        let result: Result<(), CheckedError<()>> = atomically_checked(|trans| {
+            var.write(trans, -1).map_err(Into::into)
+        });
+        assert_eq!(result, Err(CheckedError::Violated("non-negative".to_owned())));
+        assert_eq!(var.read_atomic(), 5);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| atomically(|trans| var.write(trans, -1))));
+        assert!(result.is_err());
+        assert_eq!(var.read_atomic(), 5);
+    }
+
+    #[test]
+    fn only_writes_to_dependencies_recheck() {
+        let _backend = shared_backend();
+        let (a, b) = (TVar::new(0), TVar::new(0));
+        let checks = Arc::new(AtomicUsize::new(0));
+        let (checked, counter) = (a.clone(), checks.clone());
+        atomically(|trans| {
+            let (checked, counter) = (checked.clone(), counter.clone());
+            trans.always("a counted", move |trans| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                checked.read(trans).map(|_| true)
+            })
+        });
+        let registered = checks.load(Ordering::SeqCst);
+        assert!(registered > 0);
+
+        atomically(|trans| b.write(trans, 1));
+        assert_eq!(checks.load(Ordering::SeqCst), registered);
+        atomically(|trans| a.write(trans, 1));
+        assert_eq!(checks.load(Ordering::SeqCst), registered + 1);
+    }
+
+    #[test]
+    fn dependencies_dont_keep_vars_alive() {
+        let _backend = shared_backend();
+        let inner = TVar::new(0);
+        let outer = TVar::new(inner.clone());
+        let checked = outer.clone();
+        atomically(|trans| {
+            let checked = checked.clone();
+            trans.always("inner non-negative", move |trans| {
+                let inner = checked.read(trans)?;
+                Ok(inner.read(trans)? >= 0)
+            })
+        });
+
+        atomically(|trans| outer.write(trans, TVar::new(1)));
+        assert_eq!(Arc::strong_count(inner.control_block()), 1);
+    }
+}
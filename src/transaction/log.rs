@@ -0,0 +1,293 @@
+//! The log of a transaction.
+//!
+//! Most transactions access only a few vars, so the log is a plain vector,
+//! that is searched linearly. A small bloom filter answers most lookups of
+//! vars, that are not in the log, without a search. Once the log grows
+//! larger, an index by address takes over the search.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::{Hasher, BuildHasherDefault};
+use std::slice;
+use std::sync::Arc;
+use std::vec;
+
+use super::log_var::LogVar;
+use super::super::variable::Var;
+
+/// Size of the log, up to which it is searched linearly.
+const LINEAR_SEARCH_MAX: usize = 16;
+
+/// Number of 64 bit words in the bloom filter.
+const FILTER_WORDS: usize = 4;
+
+/// Hasher for addresses, that are already well distributed after mixing.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8 | b as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = mix(n);
+    }
+}
+
+/// Spread the bits of an address over the whole word.
+fn mix(address: usize) -> u64 {
+    // Vars are allocated with at least 8 byte alignment.
+    let hash = ((address >> 3) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    hash ^ hash >> 29
+}
+
+fn address(var: &Var) -> usize {
+    var as *const Var as *const u8 as usize
+}
+
+thread_local! {
+    /// A cleared log, that the next transaction on this thread can reuse.
+    static SPARE: RefCell<Option<Log>> = RefCell::new(None);
+}
+
+#[derive(Default)]
+pub struct Log {
+    /// All vars in the order, they have been accessed first.
+    entries: Vec<(Arc<Var>, LogVar)>,
+
+    /// Two bits per var in the log.
+    filter: [u64; FILTER_WORDS],
+
+    /// Positions in `entries` by address, if `indexed` is set.
+    index: HashMap<usize, usize, BuildHasherDefault<AddressHasher>>,
+
+    /// The log is too large for a linear search.
+    indexed: bool,
+}
+
+impl Log {
+    pub fn new() -> Log {
+        Log::default()
+    }
+
+    /// Get an empty log, reusing the allocation of an earlier transaction.
+    pub fn take() -> Log {
+        SPARE.with(|spare| spare.borrow_mut().take()).unwrap_or_default()
+    }
+
+    /// Hand the allocation of the log to the next `take` on this thread.
+    pub fn recycle(mut self) {
+        self.clear();
+        // Fails, while the thread is being torn down.
+        let _ = SPARE.try_with(|spare| *spare.borrow_mut() = Some(self));
+    }
+
+    /// Bits of the var in the bloom filter.
+    fn filter_bits(address: usize) -> [(usize, u64); 2] {
+        let hash = mix(address);
+        let bit = |h: u64| ((h as usize / 64) % FILTER_WORDS, 1 << (h % 64));
+        [bit(hash >> 32), bit(hash >> 48)]
+    }
+
+    fn position(&self, var: &Var) -> Option<usize> {
+        let address = address(var);
+        let bits = Log::filter_bits(address);
+        if bits.iter().any(|&(word, bit)| self.filter[word] & bit == 0) {
+            return None;
+        }
+        if self.indexed {
+            self.index.get(&address).cloned()
+        } else {
+            self.entries.iter().position(|&(ref v, _)| self::address(&**v) == address)
+        }
+    }
+
+    pub fn get(&self, var: &Var) -> Option<&LogVar> {
+        self.position(var).map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_mut(&mut self, var: &Var) -> Option<&mut LogVar> {
+        match self.position(var) {
+            Some(i) => Some(&mut self.entries[i].1),
+            None => None,
+        }
+    }
+
+    /// Add a var, that is not in the log yet.
+    pub fn push(&mut self, var: Arc<Var>, value: LogVar) {
+        let address = address(&*var);
+        for &(word, bit) in &Log::filter_bits(address) {
+            self.filter[word] |= bit;
+        }
+        let position = self.entries.len();
+        self.entries.push((var, value));
+
+        if self.indexed {
+            self.index.insert(address, position);
+        } else if self.entries.len() > LINEAR_SEARCH_MAX {
+            let entries = &self.entries;
+            self.index.extend(entries.iter().enumerate().map(|(i, &(ref v, _))| (self::address(&**v), i)));
+            self.indexed = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter { inner: self.entries.iter() }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &LogVar> {
+        self.entries.iter().map(|&(_, ref value)| value)
+    }
+
+    /// Iterate over the entries from position `start` on.
+    pub fn values_mut_from(&mut self, start: usize) -> impl Iterator<Item = &mut LogVar> {
+        let start = cmp::min(start, self.entries.len());
+        self.entries[start..].iter_mut().map(|&mut (_, ref mut value)| value)
+    }
+
+    /// Remove all entries, but keep the allocations.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.filter = [0; FILTER_WORDS];
+        self.index.clear();
+        self.indexed = false;
+    }
+}
+
+impl Clone for Log {
+    fn clone(&self) -> Log {
+        Log {
+            entries: self.entries.clone(),
+            filter: self.filter,
+            // An unused index may still have a large allocation.
+            index: if self.indexed { self.index.clone() } else { HashMap::default() },
+            indexed: self.indexed,
+        }
+    }
+}
+
+/// Iterator over the entries of a log in the order they have been added.
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (Arc<Var>, LogVar)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Arc<Var>, &'a LogVar);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|&(ref var, ref value)| (var, value))
+    }
+}
+
+impl<'a> IntoIterator for &'a Log {
+    type Item = (&'a Arc<Var>, &'a LogVar);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for Log {
+    type Item = (Arc<Var>, LogVar);
+    type IntoIter = vec::IntoIter<(Arc<Var>, LogVar)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{Log, LINEAR_SEARCH_MAX};
+    use super::super::log_var::LogVar;
+    use super::super::super::variable::{Var, VarControlBlock};
+
+    fn vars(n: usize) -> Vec<Arc<Var>> {
+        (0..n).map(|i| VarControlBlock::new(i) as Arc<Var>).collect()
+    }
+
+    /// Log every var with its number as the written value.
+    fn log_of(vars: &[Arc<Var>]) -> Log {
+        let mut log = Log::new();
+        for (i, var) in vars.iter().enumerate() {
+            log.push(var.clone(), LogVar::Write(Arc::new(i)));
+        }
+        log
+    }
+
+    fn value(log: &Log, var: &Var) -> Option<usize> {
+        log.get(var).and_then(LogVar::written).map(|v| *v.downcast_ref::<usize>().unwrap())
+    }
+
+    #[test]
+    fn linear_search() {
+        let vars = vars(LINEAR_SEARCH_MAX);
+        let log = log_of(&vars);
+        assert!(!log.indexed);
+        for (i, var) in vars.iter().enumerate() {
+            assert_eq!(value(&log, &**var), Some(i));
+        }
+    }
+
+    #[test]
+    fn index_takes_over() {
+        let vars = vars(4 * LINEAR_SEARCH_MAX);
+        let mut log = log_of(&vars[..LINEAR_SEARCH_MAX + 1]);
+        assert!(log.indexed);
+        // Entries added after the switch are indexed as well.
+        for (i, var) in vars.iter().enumerate().skip(LINEAR_SEARCH_MAX + 1) {
+            log.push(var.clone(), LogVar::Write(Arc::new(i)));
+        }
+        for (i, var) in vars.iter().enumerate() {
+            assert_eq!(value(&log, &**var), Some(i));
+        }
+        assert_eq!(log.len(), vars.len());
+    }
+
+    #[test]
+    fn missing_vars_are_not_found() {
+        let all = vars(200);
+        let (logged, missing) = all.split_at(100);
+        for &n in &[1, LINEAR_SEARCH_MAX, logged.len()] {
+            let log = log_of(&logged[..n]);
+            // Most misses are answered by the filter, the rest by the search.
+            assert!(missing.iter().all(|var| log.get(&**var).is_none()));
+        }
+        assert!(missing.iter().all(|var| Log::new().get(&**var).is_none()));
+    }
+
+    #[test]
+    fn clear_and_reuse() {
+        let vars = vars(2 * LINEAR_SEARCH_MAX);
+        let mut log = log_of(&vars);
+        log.clear();
+        assert_eq!(log.len(), 0);
+        assert!(!log.indexed);
+        assert!(vars.iter().all(|var| log.get(&**var).is_none()));
+
+        // A recycled log comes back empty from `take`.
+        let log = log_of(&vars);
+        log.recycle();
+        let mut log = Log::take();
+        assert_eq!(log.len(), 0);
+        assert!(vars.iter().all(|var| log.get(&**var).is_none()));
+        log.push(vars[3].clone(), LogVar::Write(Arc::new(3usize)));
+        assert_eq!(value(&log, &*vars[3]), Some(3));
+        assert_eq!(value(&log, &*vars[4]), None);
+    }
+}
@@ -1,13 +1,33 @@
 use std::any::Any;
+use std::mem;
 use std::sync::Arc;
 
+use super::super::variable::Var;
+
 pub type ArcAny = Arc<Any + Send + Sync>;
 
+/// An update registered with `Transaction::commute`.
+///
+/// Gets the old value of a var and returns the new one.
+pub type CommuteFn = Arc<Fn(&ArcAny) -> ArcAny + Send + Sync>;
+
+/// Apply all updates in order to `value`.
+fn apply(updates: &[CommuteFn], value: &ArcAny) -> ArcAny {
+    updates.iter().fold(value.clone(), |value, f| f(&value))
+}
+
+/// A value, that has been read from a var, with the version the var had.
+#[derive(Clone)]
+pub struct ReadValue {
+    pub value: ArcAny,
+    pub version: usize,
+}
+
 /// LogVar is used by `Log` to track which `Var` was either read or written
 #[derive(Clone)]
 pub enum LogVar {
     /// Var has been read.
-    Read(ArcAny),
+    Read(ReadValue),
     
     /// Var has been written and no dependency on the original exists.
     ///
@@ -19,14 +39,14 @@ pub enum LogVar {
     /// Var has been read first and then written.
     ///
     /// It needs to be checked for consistency.
-    ReadWrite(ArcAny, ArcAny),
+    ReadWrite(ReadValue, ArcAny),
 
     /// Var has been read on blocked path.
     ///
     /// Don't check for consistency, but block on Var,
     /// so that the thread wakes up when the first path
     /// has been unlocked.
-    ReadObsolete(ArcAny),
+    ReadObsolete(ReadValue),
 
     /// ReadWriteObsolete(original value, temporary stored value)
     ///
@@ -35,29 +55,103 @@ pub enum LogVar {
     /// Don't check for consistency, but block on Var,
     /// so that the threat wakes up when the first path
     /// has been unlocked.
-    ReadObsoleteWrite(ArcAny, ArcAny)
+    ReadObsoleteWrite(ReadValue, ArcAny),
+
+    /// Var has not been read, but updates have been registered.
+    ///
+    /// They are applied to the latest value during the commit,
+    /// so there is nothing to check for consistency.
+    Commute(Vec<CommuteFn>),
 }
 
 
 impl LogVar {
-    /// read a value and potentially upgrade the state
-    pub fn read(&mut self) -> ArcAny {
+    /// get the value, that is visible to the transaction
+    ///
+    /// Returns `None` for `ReadObsolete`, because obsolete reads are not
+    /// validated anymore. The var has to be read again.
+    pub fn read(&self) -> Option<ArcAny> {
         use self::LogVar::*;
 
-        let this = self.clone();
-
-        match this {
+        match *self {
             // Use last read value or get written one
-            Read(v) | Write(v) | ReadWrite(_,v) | ReadObsoleteWrite(_,v)     => v,
+            Read(ref r)
+                => Some(r.value.clone()),
+            Write(ref v) | ReadWrite(_, ref v) | ReadObsoleteWrite(_, ref v)
+                => Some(v.clone()),
 
-            // Upgrade to a real Read
-            ReadObsolete(v)           => {
-                *self = Read(v.clone());
-                v
-            }
+            ReadObsolete(_) | Commute(_)
+                => None,
         }
     }
-    
+
+    /// get the original value, that the transaction depends on
+    ///
+    /// Only these values need to be checked for consistency.
+    pub fn dependency(&self) -> Option<&ReadValue> {
+        use self::LogVar::*;
+
+        match *self {
+            Read(ref v) | ReadWrite(ref v, _)
+                => Some(v),
+            Write(_) | ReadObsolete(_) | ReadObsoleteWrite(_, _) | Commute(_)
+                => None,
+        }
+    }
+
+    /// get the value, that has to be written on commit
+    ///
+    /// Returns `None` for `Commute`, because the value isn't known before the commit.
+    pub fn written(&self) -> Option<&ArcAny> {
+        use self::LogVar::*;
+
+        match *self {
+            Write(ref v) | ReadWrite(_, ref v) | ReadObsoleteWrite(_, ref v)
+                => Some(v),
+            Read(_) | ReadObsolete(_) | Commute(_)
+                => None,
+        }
+    }
+
+    /// check if the var gets written on commit
+    pub fn writes(&self) -> bool {
+        use self::LogVar::*;
+
+        match *self {
+            Write(_) | ReadWrite(_, _) | ReadObsoleteWrite(_, _) | Commute(_)
+                => true,
+            Read(_) | ReadObsolete(_)
+                => false,
+        }
+    }
+
+    /// get the value, that has to be written on commit
+    ///
+    /// Updates of `Commute` are applied to the current value of `var`,
+    /// so it must be locked against other commits.
+    pub fn commit_value(&self, var: &Var) -> Option<ArcAny> {
+        use self::LogVar::*;
+
+        match *self {
+            Commute(ref updates)
+                => Some(apply(updates, &var.value_any())),
+            _
+                => self.written().cloned(),
+        }
+    }
+
+    /// get mutable access to the value, that has to be written on commit
+    pub fn written_mut(&mut self) -> Option<&mut ArcAny> {
+        use self::LogVar::*;
+
+        match *self {
+            Write(ref mut v) | ReadWrite(_, ref mut v) | ReadObsoleteWrite(_, ref mut v)
+                => Some(v),
+            Read(_) | ReadObsolete(_) | Commute(_)
+                => None,
+        }
+    }
+
     /// write a value and potentially upgrade the state.
     pub fn write(&mut self, w: ArcAny)
     {
@@ -66,7 +160,7 @@ impl LogVar {
         let this = self.clone();
 
         *self = match this {
-            Write(_)    
+            Write(_) | Commute(_)
                 => Write(w),
 
             // Register write
@@ -79,6 +173,42 @@ impl LogVar {
         };
     }
 
+    /// make the transaction depend on `read`, unless it already depends on a value
+    pub fn depend(&mut self, read: ReadValue) {
+        use self::LogVar::*;
+
+        let this = self.clone();
+
+        *self = match this {
+            Write(w) | ReadObsoleteWrite(_, w)
+                => ReadWrite(read, w),
+
+            ReadObsolete(_)
+                => Read(read),
+
+            // Apply the updates to the value, that the transaction depends on now.
+            Commute(updates) => {
+                let w = apply(&updates, &read.value);
+                ReadWrite(read, w)
+            }
+
+            other
+                => other,
+        };
+    }
+
+    /// register an update, that is applied on commit
+    ///
+    /// Must only be called on vars, whose value the transaction doesn't know.
+    pub fn commute(&mut self, f: CommuteFn) {
+        use self::LogVar::*;
+
+        match *self {
+            Commute(ref mut updates) => updates.push(f),
+            ref mut other => *other = Commute(vec![f]),
+        }
+    }
+
     /// turn this into an obsolete version
     pub fn obsolete(self) -> Option<LogVar>
     {
@@ -87,13 +217,48 @@ impl LogVar {
             .map(|a| ReadObsolete(a))
     }
 
-    /// Ignore all Write... and get the original value of a Var.
-    pub fn into_read_value(self) -> Option<ArcAny> {
+    /// stop validating a plain read, but keep blocking on it
+    pub fn release(&mut self) {
+        use self::LogVar::*;
+
+        if let Read(_) = *self {
+            // Move the value instead of cloning it. Cloning touches the
+            // reference count, that all readers of the var share.
+            // An empty `Commute` doesn't allocate, so it's a cheap placeholder.
+            if let Read(r) = mem::replace(self, Commute(Vec::new())) {
+                *self = ReadObsolete(r);
+            }
+        }
+    }
+
+    /// drop the write of this var and keep only the read, if there is one
+    pub fn into_read(self) -> Option<LogVar>
+    {
         use self::LogVar::*;
         match self {
-            Read(v) | ReadWrite(v,_) | ReadObsolete(v) | ReadObsoleteWrite(v,_)
+            Read(v) | ReadWrite(v, _)
+                => Some(Read(v)),
+            ReadObsolete(v) | ReadObsoleteWrite(v, _)
+                => Some(ReadObsolete(v)),
+            Write(_) | Commute(_)
+                => None,
+        }
+    }
+
+    /// Ignore all Write... and get the original value of a Var.
+    pub fn into_read_value(self) -> Option<ReadValue> {
+        self.read_value().cloned()
+    }
+
+    /// Ignore all Write... and get a reference to the original value of a Var.
+    ///
+    /// This includes obsolete reads, so it is used to block on all vars.
+    pub fn read_value(&self) -> Option<&ReadValue> {
+        use self::LogVar::*;
+        match *self {
+            Read(ref v) | ReadWrite(ref v,_) | ReadObsolete(ref v) | ReadObsoleteWrite(ref v,_)
                 => Some(v),
-            Write(_)    => None,
+            Write(_) | Commute(_) => None,
         }
     }
 }
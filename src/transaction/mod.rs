@@ -1,162 +1,1467 @@
 pub mod control_block;
 pub mod log_var;
+pub mod log;
+pub mod invariant;
+pub mod backend;
+pub mod contention;
+pub mod wait;
 
-use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::mem;
-use std::sync::{Arc};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::any::Any;
-use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-use super::variable::{TVar, VarControlBlock};
+use self::control_block::ControlBlock;
+use self::log_var::{LogVar, ReadValue, ArcAny, CommuteFn};
+use self::log::Log;
+use self::log_var::LogVar::*;
+use self::invariant::Invariant;
+use self::backend::StmBackend;
+use self::contention::ContentionManager;
+use super::variable::{TVar, Var, VarControlBlock};
 use super::result::*;
 use super::result::StmError::*;
+use super::result::TransactionError::*;
 
-type ArcAny = Arc<Any + Send + Sync>;
+/// Options for running a transaction.
+#[derive(Clone, Copy, Default)]
+struct Options {
+    /// Give up, once this point in time has passed.
+    ///
+    /// This includes the time spent blocked in `retry` and the time spent
+    /// waiting for an irrevocable transaction to let the commit through.
+    deadline: Option<Instant>,
+
+    /// Give up after the closure has been run this many times.
+    max_attempts: Option<usize>,
+
+    /// Start without a read log. See `Transaction::run_read_only`.
+    read_only: bool,
+
+    /// Start with an elastic prefix. See `Transaction::run_elastic`.
+    elastic: bool,
+
+    /// Only check writes for conflicts. See `Transaction::run_snapshot_isolated`.
+    snapshot_isolated: bool,
+}
+
+/// An action, that runs after the transaction has finished.
+type Hook = Box<FnOnce()>;
+
+/// A saved state of a transaction, created by `Transaction::savepoint`.
+pub struct Savepoint {
+    vars: Log,
+    commit_hooks: usize,
+    abort_hooks: usize,
+}
 
-static GLOBAL_SEQ_LOCK: AtomicUsize = ATOMIC_USIZE_INIT;
 pub struct Transaction {
+    /// Algorithm, that synchronizes this transaction with others.
+    backend: &'static StmBackend,
+
+    /// True between the start of an attempt and its commit or abort.
+    running: bool,
+
+    /// Point in time, that all reads are consistent with.
+    ///
+    /// The meaning depends on the backend.
     snapshot: usize,
-    writevars: BTreeMap<Arc<VarControlBlock>, ArcAny>,
-    readvars: BTreeMap<Arc<VarControlBlock>, ArcAny>,
+
+    /// Reads are not logged and writes are forbidden.
+    read_only: bool,
+    vars: Log,
+
+    /// Nothing has been written yet in an elastic transaction.
+    ///
+    /// Only the last read is kept for validation.
+    elastic: bool,
+
+    /// Number of entries at the start of the log, that are not
+    /// validated anymore, because the elastic prefix released them.
+    released: usize,
+
+    /// Reads see a pinned snapshot and only writes and ensured reads are validated.
+    snapshot_isolated: bool,
+
+    /// Snapshot, that a snapshot isolated attempt reads from.
+    ///
+    /// Kept apart from `snapshot`, which the backend may move forward.
+    pinned: Option<usize>,
+
+    /// Run once after a successful commit.
+    commit_hooks: Vec<Hook>,
+
+    /// Run once after the transaction has been aborted by the user.
+    abort_hooks: Vec<Hook>,
+
+    /// Vars read while checking an invariant.
+    tracked_reads: Option<BTreeSet<Arc<Var>>>,
+
+    /// Held, once the transaction has become irrevocable.
+    irrevocable: Option<backend::CommitsBlocked>,
+
+    /// The attempt has been made irrevocable, because of too many conflicts.
+    serialized: bool,
+
+    /// Number of the current attempt, starting at 1.
+    attempt: usize,
+
+    /// Give up waiting for other transactions, once this has passed.
+    ///
+    /// See `Options::deadline`.
+    deadline: Option<Instant>,
 }
 
 impl Transaction {
-    fn new(ss: usize) -> Transaction {
+    fn new(backend: &'static StmBackend) -> Transaction {
         Transaction { 
-            snapshot: ss,
-            writevars: BTreeMap::new(),
-            readvars: BTreeMap::new()
+            backend: backend,
+            running: false,
+            snapshot: 0,
+            read_only: false,
+            vars: Log::take(),
+            elastic: false,
+            released: 0,
+            snapshot_isolated: false,
+            pinned: None,
+            commit_hooks: Vec::new(),
+            abort_hooks: Vec::new(),
+            tracked_reads: None,
+            irrevocable: None,
+            serialized: false,
+            attempt: 0,
+            deadline: None,
         }
     }
 
     pub fn run<T, F>(f: F) -> T 
     where F: Fn(&mut Transaction) -> StmResult<T>,
     {
-        let mut ss = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
-        while (ss & 1) != 0 {
-            ss = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
+        match Transaction::run_result(|trans| f(trans).map_err(Stm)) {
+            Ok(t) => t,
+            Err(()) => unreachable!(),
+        }
+    }
+
+    /// Run a transaction, that may be aborted with a user error.
+    ///
+    /// On `Abort` all writes are discarded and the error is returned.
+    ///
+    /// If `f` panics, the transaction is aborted the same way and
+    /// the panic is propagated to the caller. If the transaction would
+    /// violate an invariant, it is aborted and the caller panics.
+    /// Use `run_checked` to get an error instead.
+    pub fn run_result<T, E, F>(f: F) -> Result<T, E>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        match Transaction::run_with(f, Options::default()) {
+            Some(result) => result,
+            None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction, that may be aborted with a user error or by an invariant.
+    ///
+    /// Like `run_result`, but a violated invariant is returned
+    /// as `CheckedError::Violated` with the name of the invariant.
+    pub fn run_checked<T, E, F>(f: F) -> Result<T, CheckedError<E>>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        let mut manager = contention::contention_policy().manager();
+        match Transaction::run_managed_with(f, Options::default(), &mut *manager) {
+            Some(result) => result,
+            None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction, but give up once `timeout` has passed.
+    ///
+    /// Returns `None` if the transaction didn't commit in time, either
+    /// because of conflicts or because it was blocked in `retry`.
+    pub fn run_timeout<T, F>(timeout: Duration, f: F) -> Option<T>
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let options = Options {
+            deadline: Some(Instant::now() + timeout),
+            ..Options::default()
+        };
+        match Transaction::run_with(|trans| f(trans).map_err(Stm), options) {
+            Some(Ok(t)) => Some(t),
+            Some(Err(())) => unreachable!(),
+            None => None,
         }
-        let mut transaction = Transaction::new(ss);
+    }
+
+    /// Run a transaction, but give up after `max_attempts` runs of `f`.
+    ///
+    /// A `retry` counts as an attempt and doesn't block, so this never waits
+    /// for other transactions longer than their commits take.
+    pub fn run_bounded<T, F>(max_attempts: usize, f: F) -> Result<T, Exhausted>
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let options = Options {
+            max_attempts: Some(max_attempts),
+            ..Options::default()
+        };
+        match Transaction::run_with(|trans| f(trans).map_err(Stm), options) {
+            Some(Ok(t)) => Ok(t),
+            Some(Err(())) => unreachable!(),
+            None => Err(Exhausted),
+        }
+    }
+
+    /// Run a transaction, that only reads.
+    ///
+    /// All reads see the state at the start of the transaction. Vars keep
+    /// values overwritten in the meantime, so commits of other transactions
+    /// don't make it fail. Reads are not logged and the commit has nothing to
+    /// validate or publish, so it doesn't take any lock.
+    ///
+    /// Old values are only kept as long as a read-only transaction may
+    /// need them, so a long running one holds on to more memory. If `f`
+    /// calls `retry`, the transaction falls back to a normal run, which
+    /// knows, which vars to wait for.
+    ///
+    /// # Panics
+    ///
+    /// Panics, if `f` writes to a var.
+    pub fn run_read_only<T, F>(f: F) -> T
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        match Transaction::run_with(|trans| f(trans).map_err(Stm), options) {
+            Some(Ok(t)) => t,
+            Some(Err(())) | None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction, that searches a structure before changing it.
+    ///
+    /// Until the first write, every read releases the reads before the
+    /// previous one, like the hand-over-hand locking of a linked list. So
+    /// the reads of a traversal are only validated in pairs and a write to
+    /// a node, that has been passed already, doesn't make it fail. The last
+    /// two reads before the first write and everything after it are
+    /// validated as usual.
+    ///
+    /// This is only correct, if every read depends on the previous one
+    /// alone, like following the `next` pointers of a sorted list.
+    pub fn run_elastic<T, F>(f: F) -> T
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let options = Options {
+            elastic: true,
+            ..Options::default()
+        };
+        match Transaction::run_with(|trans| f(trans).map_err(Stm), options) {
+            Some(Ok(t)) => t,
+            Some(Err(())) | None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction under snapshot isolation.
+    ///
+    /// All reads see the state at the start of the transaction, like in
+    /// `run_read_only`, and they are not validated. At commit only the
+    /// written vars are checked: if another transaction has written one
+    /// of them since the start, this one runs again.
+    ///
+    /// This allows write skew: two transactions, that read the same vars
+    /// and write different ones, both commit, even if each would have
+    /// decided differently after the other. Read the vars, that such a
+    /// decision depends on, with `ensure`.
+    pub fn run_snapshot_isolated<T, F>(f: F) -> T
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let options = Options {
+            snapshot_isolated: true,
+            ..Options::default()
+        };
+        match Transaction::run_with(|trans| f(trans).map_err(Stm), options) {
+            Some(Ok(t)) => t,
+            Some(Err(())) | None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction with `manager` instead of the selected contention manager.
+    pub fn run_managed<T, F>(manager: &mut ContentionManager, f: F) -> T
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let result = Transaction::run_managed_with(|trans| f(trans).map_err(Stm), Options::default(), manager);
+        match expect_valid(result) {
+            Some(Ok(t)) => t,
+            Some(Err(())) | None => unreachable!(),
+        }
+    }
+
+    /// Run a transaction until it commits, aborts or exceeds the limits in `options`.
+    ///
+    /// Returns `None` if one of the limits has been exceeded.
+    fn run_with<T, E, F>(f: F, options: Options) -> Option<Result<T, E>>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        let mut manager = contention::contention_policy().manager();
+        expect_valid(Transaction::run_managed_with(f, options, &mut *manager))
+    }
+
+    /// Like `run_with`, but resolve conflicts with `manager`
+    /// and return a violated invariant as an error.
+    fn run_managed_with<T, E, F>(f: F, options: Options, manager: &mut ContentionManager)
+        -> Option<Result<T, CheckedError<E>>>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            Transaction::run_attempts(f, options, manager)
+        }));
+        manager.finished();
+        match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    fn run_attempts<T, E, F>(f: F, options: Options, manager: &mut ContentionManager)
+        -> Option<Result<T, CheckedError<E>>>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        let mut transaction = Transaction::new(backend::current());
+        transaction.read_only = options.read_only;
+        transaction.snapshot_isolated = options.snapshot_isolated;
+        transaction.deadline = options.deadline;
+        let serialize_after = contention::serialize_after();
+        let mut attempts = 0;
+        let mut conflicts = 0;
 
         loop {
-            match f(&mut transaction) {
-                Ok(t) => {
-                    if transaction.commit() {
-                        return t;
+            if options.max_attempts.map_or(false, |max| attempts >= max) {
+                return None;
+            }
+            attempts += 1;
+            transaction.attempt = attempts;
+            if !transaction.begin() {
+                transaction.clear();
+                return None;
+            }
+            transaction.elastic = options.elastic;
+            if serialize_after.map_or(false, |max| conflicts >= max) {
+                if !transaction.serialize() {
+                    transaction.clear();
+                    return None;
+                }
+            }
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let t = f(&mut transaction)?;
+                match transaction.check_invariants()? {
+                    None => Ok(Ok(t)),
+                    Some(name) => Ok(Err(name)),
+                }
+            }));
+            let result = match result {
+                Ok(result) => result,
+                Err(payload) => {
+                    transaction.clear();
+                    panic::resume_unwind(payload);
+                }
+            };
+
+            match result {
+                Ok(Ok(t)) => match transaction.commit() {
+                    Some(true) => {
+                        run_hooks(mem::replace(&mut transaction.commit_hooks, Vec::new()));
+                        return Some(Ok(t));
+                    }
+                    Some(false) => {
+                        conflicts += 1;
+                        manager.conflict(attempts, transaction.vars.len());
+                    }
+                    None => {
+                        transaction.clear();
+                        return None;
                     }
+                },
+                Ok(Err(name)) => {
+                    let hooks = mem::replace(&mut transaction.abort_hooks, Vec::new());
+                    transaction.clear();
+                    run_hooks(hooks);
+                    return Some(Err(CheckedError::Violated(name)));
+                }
+                Err(Abort(err)) => {
+                    let hooks = mem::replace(&mut transaction.abort_hooks, Vec::new());
+                    transaction.clear();
+                    run_hooks(hooks);
+                    return Some(Err(CheckedError::Abort(err)));
+                }
+                Err(Stm(Retry)) if transaction.is_irrevocable() && !transaction.serialized => {
+                    transaction.clear();
+                    panic!("retry in an irrevocable transaction");
+                }
+                Err(Stm(Retry)) => {
+                    // The attempt has to end before blocking, so
+                    // that it doesn't hold up other transactions.
+                    transaction.end();
+                    if transaction.read_only {
+                        // There are no reads to wait for. Run again with a log.
+                        transaction.read_only = false;
+                    } else if options.max_attempts.is_none() {
+                        // A bounded transaction must not block for an unbounded time.
+                        transaction.wait_for_change(options.deadline);
+                    }
+                }
+                Err(Stm(Failure)) => {
+                    conflicts += 1;
+                    manager.conflict(attempts, transaction.vars.len());
                 }
-                Err(_) => { }
             }
+
             transaction.clear();
+            if options.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return None;
+            }
         }
     }
 
-    fn downcast<T: Any + Clone>(var: Arc<Any>) -> T {
-        var.downcast_ref::<T>()
-           .expect("Vars with different types and same address")
-           .clone()
+    pub fn read<T: Send + Sync + Any + Clone>(&mut self, var: &TVar<T>) -> StmResult<T> {
+        let value = self.read_arc(var.control_block())?;
+        Ok((*value).clone())
     }
 
-    pub fn read<T: Send + Sync + Any + Clone>(&mut self, var: &TVar<T>) -> StmResult<T> {
-        let ctrl = var.control_block().clone();
-        match self.writevars.get(&ctrl) {
-            Some(entry) => {let value = entry.clone();
-                                    return Ok(Transaction::downcast(value));}
-            None => { }
-        }
-        let mut value = var.read_ref_atomic();
-        while self.snapshot != GLOBAL_SEQ_LOCK.load(Ordering::SeqCst) {
-            match self.validate() {
-                None => { return Err(Retry); }
-                Some(ss) => {
-                    self.snapshot = ss;
-                    value = var.read_ref_atomic();
+    /// Read a var without cloning its value.
+    pub fn read_ref<T: Send + Sync + Any + Clone>(&mut self, var: &TVar<T>) -> StmResult<Arc<T>> {
+        self.read_arc(var.control_block())
+    }
+
+    fn read_arc<T: Send + Sync + Any>(&mut self, ctrl: &Arc<VarControlBlock<T>>) -> StmResult<Arc<T>> {
+        if let Some(ref mut reads) = self.tracked_reads {
+            reads.insert(ctrl.clone());
+        }
+        if self.read_only {
+            return Ok(ctrl.read_at(self.snapshot).0);
+        }
+        if let Some(value) = self.vars.get(&**ctrl).and_then(LogVar::read) {
+            return Ok(VarControlBlock::cast(value));
+        }
+        if let Some(pinned) = self.pinned {
+            match self.vars.get(&**ctrl) {
+                Some(&ReadObsolete(_)) => {}
+                Some(_) => {
+                    // Apply pending commutes to the value of the snapshot.
+                    self.depend_on_snapshot(ctrl);
+                    let value = self.vars.get(&**ctrl).and_then(LogVar::read);
+                    return Ok(VarControlBlock::cast(value.expect("value of a commuted var")));
                 }
+                None => {
+                    let (value, version) = ctrl.read_at(pinned);
+                    // Not validated, but a later `retry` blocks on it.
+                    let read = ReadValue { value: value.clone(), version: version };
+                    self.vars.push(ctrl.clone(), ReadObsolete(read));
+                    return Ok(value);
+                }
+            }
+            return Ok(ctrl.read_at(pinned).0);
+        }
+        if self.elastic {
+            self.release_elastic();
+        }
+        let backend = self.backend;
+        let mut value = None;
+        let version = backend.read(self, &**ctrl, &mut || value = Some(ctrl.read_value().clone()))?;
+        let value = value.expect("value loaded by the backend");
+        let read = ReadValue { value: value.clone(), version: version };
+        match self.vars.get_mut(&**ctrl) {
+            // An obsolete read gets upgraded and pending commutes are applied.
+            Some(entry) => {
+                entry.depend(read);
+                let value = entry.read().expect("value of a read var");
+                Ok(VarControlBlock::cast(value))
+            }
+            None => {
+                self.vars.push(ctrl.clone(), Read(read));
+                Ok(value)
             }
         }
-        let ctrl = var.control_block().clone();
-        self.readvars.insert(ctrl, value.clone());
-        Ok(Transaction::downcast(value))
     }
 
     pub fn write<T: Any + Send + Sync + Clone>(&mut self, var: &TVar<T>, value: T) -> StmResult<()> {
-        let boxed = Arc::new(value);
-        let ctrl = var.control_block().clone();
-        self.writevars.insert(ctrl, boxed);
+        self.write_arc(var.control_block(), Arc::new(value));
         Ok(())
     }
 
-    fn clear(&mut self) {
-        self.writevars.clear();
-        self.readvars.clear();
-        let mut ss = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
-        while (ss & 1) != 0 {
-            ss = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
+    fn write_arc<T: Send + Sync + Any>(&mut self, ctrl: &Arc<VarControlBlock<T>>, value: ArcAny) {
+        if self.pinned.is_some() {
+            // A write conflicts with all writes since the snapshot.
+            self.depend_on_snapshot(ctrl);
         }
-        self.snapshot = ss;
+        self.write_any(ctrl.clone(), value);
     }
 
-    fn validate(&mut self) -> Option<usize> {
-        loop {
-            let time = GLOBAL_SEQ_LOCK.load(Ordering::SeqCst);
-            if time & 1 != 0 {
-                continue;
-            }
-            let vars = mem::replace(&mut self.readvars, BTreeMap::new());
-            let mut read_vec = Vec::new();
-            for (var, value) in &vars {
-                match value { 
-                        ref original => {
-                        let lock = var.value.read().unwrap();
-                        if !same_address(&lock, &original) {
-                            mem::drop(read_vec);
-                            return None;
+    /// Make a snapshot isolated transaction fail, if `ctrl` changes after its snapshot.
+    fn depend_on_snapshot<T: Send + Sync + Any>(&mut self, ctrl: &Arc<VarControlBlock<T>>) {
+        let pinned = self.pinned.expect("snapshot of a snapshot isolated transaction");
+        match self.vars.get_mut(&**ctrl) {
+            Some(ref entry) if entry.dependency().is_some() => {}
+            Some(entry) => {
+                let (value, version) = ctrl.read_at(pinned);
+                entry.depend(ReadValue { value: value, version: version });
+            }
+            None => {
+                let (value, version) = ctrl.read_at(pinned);
+                self.vars.push(ctrl.clone(), Read(ReadValue { value: value, version: version }));
+            }
+        }
+    }
+
+    /// Read a var and make sure, that it is still current, when the transaction commits.
+    ///
+    /// This only makes a difference under snapshot isolation, where plain
+    /// reads are not validated. Ensure the reads, that the writes of the
+    /// transaction depend on, to rule out write skew.
+    pub fn ensure<T: Send + Sync + Any + Clone>(&mut self, var: &TVar<T>) -> StmResult<T> {
+        if self.pinned.is_some() {
+            self.depend_on_snapshot(var.control_block());
+        }
+        self.read(var)
+    }
+
+    fn write_any(&mut self, ctrl: Arc<Var>, value: ArcAny) {
+        assert!(!self.read_only, "write in a read-only transaction");
+        self.elastic = false;
+        match self.vars.get_mut(&*ctrl) {
+            Some(entry) => entry.write(value),
+            None => self.vars.push(ctrl, Write(value)),
+        }
+    }
+
+    /// Change the value of a var in place.
+    ///
+    /// The value is only cloned, if it is shared, which is the case on
+    /// the first modification in a transaction. Later modifications of the
+    /// same var work on the private copy, unless a savepoint still holds it.
+    pub fn modify<T, F, R>(&mut self, var: &TVar<T>, f: F) -> StmResult<R>
+    where T: Send + Sync + Any + Clone,
+          F: FnOnce(&mut T) -> R,
+    {
+        let ctrl = var.control_block();
+        if self.vars.get(&**ctrl).and_then(LogVar::written).is_none() {
+            let value = self.read_arc(ctrl)?;
+            self.write_arc(ctrl, value);
+        }
+        let value = self.vars.get_mut(&**ctrl)
+            .and_then(LogVar::written_mut)
+            .expect("modified var has been written");
+        if VarControlBlock::<T>::cast_mut(value).is_none() {
+            let copy: T = (*VarControlBlock::<T>::cast(value.clone())).clone();
+            *value = Arc::new(copy);
+        }
+        let value = VarControlBlock::<T>::cast_mut(value)
+            .expect("private copy of a modified var");
+        Ok(f(value))
+    }
+
+    /// Update a var with `f` without depending on its current value.
+    ///
+    /// `f` is applied to the latest value, while the transaction commits,
+    /// so concurrent updates of the same var don't conflict. This suits
+    /// counters and statistics, that many transactions change. `f` runs
+    /// while the var is locked, so it should be cheap.
+    ///
+    /// Reading the var later in the transaction applies `f` to the value read
+    /// and makes the transaction depend on it again. If the transaction knows
+    /// the value already, `f` is applied to it right away.
+    pub fn commute<T, F>(&mut self, var: &TVar<T>, f: F) -> StmResult<()>
+    where T: Send + Sync + Any + Clone,
+          F: Fn(&mut T) + Send + Sync + 'static,
+    {
+        let ctrl = var.control_block();
+        if self.read_only || self.vars.get(&**ctrl).and_then(LogVar::read).is_some() {
+            return self.modify(var, f);
+        }
+        self.elastic = false;
+        let update: CommuteFn = Arc::new(move |value: &ArcAny| -> ArcAny {
+            let mut value = (*VarControlBlock::<T>::cast(value.clone())).clone();
+            f(&mut value);
+            Arc::new(value)
+        });
+        match self.vars.get_mut(&**ctrl) {
+            Some(entry) => entry.commute(update),
+            None => self.vars.push(ctrl.clone(), Commute(vec![update])),
+        }
+        Ok(())
+    }
+
+    /// Stop validating an earlier read of `var`.
+    ///
+    /// Afterwards writes of other transactions to `var` don't cause a
+    /// conflict anymore. This is meant for search structures: a traversal
+    /// of a linked list can release the nodes it has passed, so that an
+    /// insert somewhere behind it doesn't abort it. The transaction is only
+    /// correct, if it doesn't depend on released values anymore.
+    ///
+    /// A later `retry` still blocks on the var and reading it again adds it
+    /// back. Vars, that have been written, are not released.
+    pub fn release<T: Send + Sync + Any + Clone>(&mut self, var: &TVar<T>) {
+        if let Some(entry) = self.vars.get_mut(&**var.control_block()) {
+            entry.release();
+        }
+    }
+
+    /// Run `f` and release all vars, that it reads for the first time.
+    ///
+    /// See `release` for when this is safe.
+    pub fn release_reads<T, F>(&mut self, f: F) -> StmResult<T>
+    where F: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        let start = self.vars.len();
+        let result = f(self);
+        for entry in self.vars.values_mut_from(start) {
+            entry.release();
+        }
+        result
+    }
+
+    /// Release all reads of the elastic prefix except the last one.
+    fn release_elastic(&mut self) {
+        let keep = self.vars.len().saturating_sub(1);
+        if keep > self.released {
+            let count = keep - self.released;
+            for entry in self.vars.values_mut_from(self.released).take(count) {
+                entry.release();
+            }
+            self.released = keep;
+        }
+    }
+
+    /// Run `f` once the transaction has committed.
+    ///
+    /// This is the place for side effects like I/O, which must not happen
+    /// inside of the transaction. `f` runs after all locks have been released.
+    /// It is dropped, if this attempt of the transaction fails or rolls back.
+    pub fn on_commit<F>(&mut self, f: F)
+    where F: FnOnce() + 'static
+    {
+        self.commit_hooks.push(Box::new(f));
+    }
+
+    /// Run `f` if the transaction is aborted with a user error.
+    ///
+    /// Like `on_commit`, it is dropped, if this attempt fails or rolls back.
+    pub fn on_abort<F>(&mut self, f: F)
+    where F: FnOnce() + 'static
+    {
+        self.abort_hooks.push(Box::new(f));
+    }
+
+    /// Make sure, that the transaction commits.
+    ///
+    /// Once this returns, the transaction won't be run again, so `f` may do
+    /// things, that can't be undone, like I/O. In return no other transaction
+    /// can commit writes, until this one has finished. Only one transaction
+    /// is irrevocable at a time.
+    ///
+    /// Fails, if earlier reads have become invalid or if the deadline of
+    /// the transaction passes, while it waits for another irrevocable one.
+    /// Then the transaction is restarted as usual. `abort` still discards the writes, but
+    /// calling `retry` afterwards panics, because it could only
+    /// continue by running the transaction again.
+    pub fn become_irrevocable(&mut self) -> StmResult<()> {
+        if self.is_irrevocable() {
+            // From now on a serialized attempt must not be run again either.
+            self.serialized = false;
+            return Ok(());
+        }
+        // Wait for running commits. No transaction commits afterwards,
+        // so the reads stay valid, if they are valid now.
+        let gate = match backend::block_commits(self.deadline) {
+            Some(gate) => gate,
+            None => return Err(Failure),
+        };
+        if self.pinned.is_some() {
+            // Writes after this point must not conflict, so nothing
+            // may have been committed since the snapshot.
+            if self.vars.len() == 0 {
+                self.repin();
+            } else if backend::clock() != self.snapshot {
+                return Err(Failure);
+            }
+        } else if !self.read_only {
+            let backend = self.backend;
+            match backend.validate(self) {
+                Some(ss) => { self.snapshot = ss; }
+                None => { return Err(Failure); }
+            }
+        }
+        self.irrevocable = Some(gate);
+        Ok(())
+    }
+
+    /// Check if this attempt is guaranteed to commit.
+    ///
+    /// This is the case after `become_irrevocable` and in serialized attempts.
+    pub fn is_irrevocable(&self) -> bool {
+        self.irrevocable.is_some()
+    }
+
+    /// Check if this attempt runs serialized after too many conflicts.
+    ///
+    /// See `set_serialize_after`.
+    pub fn is_serialized(&self) -> bool {
+        self.serialized
+    }
+
+    /// Get the number of the current attempt, starting at 1.
+    ///
+    /// It grows with every conflict and every `retry`.
+    pub fn attempt(&self) -> usize {
+        self.attempt
+    }
+
+    /// Stop other transactions from committing for the rest of the attempt.
+    ///
+    /// Called at the start of the attempt, so there are no reads,
+    /// that could be invalid, and the attempt is sure to commit.
+    ///
+    /// Returns `false`, if the deadline passed while waiting for other commits.
+    fn serialize(&mut self) -> bool {
+        // Taken after `begin`, because the global lock backend
+        // acquires its mutex there and commits wait for the gate.
+        match backend::block_commits(self.deadline) {
+            Some(gate) => self.irrevocable = Some(gate),
+            None => return false,
+        }
+        self.serialized = true;
+        if self.pinned.is_some() {
+            self.repin();
+        }
+        true
+    }
+
+    /// Move the snapshot of a snapshot isolated attempt to the current time.
+    ///
+    /// Only allowed before the first read.
+    fn repin(&mut self) {
+        if let Some(pinned) = self.pinned.take() {
+            backend::unpin_snapshot(pinned);
+        }
+        let pinned = backend::pin_snapshot();
+        self.pinned = Some(pinned);
+        self.snapshot = pinned;
+    }
+
+    /// Register an invariant, that must hold after every commit.
+    ///
+    /// `f` is checked when this transaction commits and afterwards by every
+    /// transaction, that writes to one of the vars read by `f`. Writes done by
+    /// `f` are discarded. If a transaction would violate the invariant, it
+    /// is aborted. `atomically_checked` returns the `name` of the invariant
+    /// then, all other ways to run a transaction panic with it.
+    pub fn always<F>(&mut self, name: &str, f: F) -> StmResult<()>
+    where F: Fn(&mut Transaction) -> StmResult<bool> + Send + Sync + 'static
+    {
+        invariant::add_registered();
+        let registry = invariant::registry();
+        let mut invariants = registry.read(self)?;
+        invariants.push(Arc::new(Invariant::new(name, f)));
+        registry.write(self, invariants)
+    }
+
+    /// Check all invariants, that may be broken by the writes of this transaction.
+    ///
+    /// Returns the name of the first violated invariant.
+    ///
+    /// Without any registered invariant, the registry isn't read at all. A
+    /// transaction, that started before the first registration committed,
+    /// may therefore commit after it without being checked.
+    fn check_invariants(&mut self) -> StmResult<Option<String>> {
+        if !invariant::any_registered() {
+            return Ok(None);
+        }
+
+        let written: Vec<_> = self.vars.iter()
+            .filter(|&(_, value)| value.writes())
+            .map(|(var, _)| var.clone())
+            .collect();
+        if written.is_empty() {
+            return Ok(None);
+        }
+
+        let invariants = invariant::registry().read(self)?;
+        for invariant in invariants.iter().filter(|inv| inv.affected_by(&written)) {
+            let savepoint = self.savepoint();
+            self.tracked_reads = Some(BTreeSet::new());
+            let holds = invariant.check(self);
+            let reads = self.tracked_reads.take().unwrap_or_default();
+            self.rollback_to(savepoint);
+
+            invariant.add_dependencies(reads);
+            if !holds? {
+                return Ok(Some(invariant.name().to_owned()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run `first` and, if it calls `retry`, run `second` instead.
+    ///
+    /// The writes of `first` are rolled back before `second` runs. If both
+    /// of them call `retry`, the transaction blocks until one of the vars,
+    /// that has been read by either branch, changes.
+    pub fn or<T, F1, F2>(&mut self, first: F1, second: F2) -> StmResult<T>
+    where F1: Fn(&mut Transaction) -> StmResult<T>,
+          F2: Fn(&mut Transaction) -> StmResult<T>,
+    {
+        // Create a backup of the log.
+        let backup = self.savepoint();
+
+        match first(self) {
+            Err(Retry) => {
+                // Restore the log and run the other branch on it.
+                let first_vars = self.restore(backup);
+                let result = second(self);
+                self.combine(first_vars);
+                result
+            }
+            // Return success and failure directly.
+            x => x,
+        }
+    }
+
+    /// Run `f` and handle an abort of it with `handler`.
+    ///
+    /// If `f` aborts, its writes are rolled back and `handler` is called
+    /// with the error. The reads of `f` are kept, because the decision
+    /// to abort depends on them. `retry` and failures are passed through.
+    pub fn catch<T, E, E2, F, H>(&mut self, f: F, handler: H) -> TransactionResult<T, E2>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+          H: Fn(&mut Transaction, E) -> TransactionResult<T, E2>,
+    {
+        match self.nested(f)? {
+            Ok(t) => Ok(t),
+            Err(err) => handler(self, err),
+        }
+    }
+
+    /// Run `f` as a closed nested transaction.
+    ///
+    /// If `f` succeeds, its writes become part of this transaction.
+    /// If it aborts, only the writes of `f` are rolled back and the error
+    /// is returned as `Ok(Err(err))`. In both cases the reads of `f` stay in
+    /// the log and are validated with the rest of the transaction.
+    pub fn nested<T, E, F>(&mut self, f: F) -> StmResult<Result<T, E>>
+    where F: Fn(&mut Transaction) -> TransactionResult<T, E>,
+    {
+        let savepoint = self.savepoint();
+
+        match f(self) {
+            Ok(t) => Ok(Ok(t)),
+            Err(Abort(err)) => {
+                self.rollback_to(savepoint);
+                Ok(Err(err))
+            }
+            Err(Stm(err)) => Err(err),
+        }
+    }
+
+    /// Remember the current state of the transaction.
+    ///
+    /// All writes done after this call can be undone with `rollback_to`.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            vars: self.vars.clone(),
+            commit_hooks: self.commit_hooks.len(),
+            abort_hooks: self.abort_hooks.len(),
+        }
+    }
+
+    /// Reset the transaction to `savepoint` and return the discarded log.
+    fn restore(&mut self, savepoint: Savepoint) -> Log {
+        self.commit_hooks.truncate(savepoint.commit_hooks);
+        self.abort_hooks.truncate(savepoint.abort_hooks);
+        mem::replace(&mut self.vars, savepoint.vars)
+    }
+
+    /// Undo all writes since `savepoint` was taken.
+    ///
+    /// Hooks registered since then are dropped as well. Reads done since then
+    /// are kept, because whatever made the caller roll back may depend on them.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        let vars = self.restore(savepoint);
+        for (var, value) in vars {
+            if let Some(read) = value.into_read() {
+                match self.vars.get_mut(&*var) {
+                    Some(entry) => {
+                        // An obsolete read may have been upgraded in between.
+                        if let ReadObsolete(_) = *entry {
+                            *entry = read;
                         }
-                        read_vec.push(lock);
                     }
+                    None => self.vars.push(var, read),
                 }
             }
-            mem::drop(read_vec);
-            if time == GLOBAL_SEQ_LOCK.load(Ordering::SeqCst) {
-                return Some(time);
+        }
+    }
+
+    /// Add the reads of an abandoned branch as obsolete reads.
+    ///
+    /// They are not validated anymore, but a later `retry` blocks on them.
+    fn combine(&mut self, other: Log) {
+        for (var, value) in other {
+            if let Some(value) = value.obsolete() {
+                if self.vars.get(&*var).is_none() {
+                    self.vars.push(var, value);
+                }
             }
         }
     }
 
-    fn commit(&mut self) -> bool {
-        if self.writevars.is_empty() {
-            return true;
+    /// Block until one of the read vars has changed.
+    ///
+    /// Called after the transaction invoked `retry`. The transaction registers
+    /// itself on every read var and only goes to sleep, if none of them has
+    /// been changed in the meantime. Otherwise it returns immediately.
+    ///
+    /// If a `deadline` is given, it stops waiting once it has passed.
+    fn wait_for_change(&mut self, deadline: Option<Instant>) {
+        let ctrl = Arc::new(ControlBlock::new());
+
+        // Register before checking the values, so that a commit
+        // between the check and the wait can't be missed.
+        let reads: Vec<_> = self.vars.iter()
+            .filter_map(|(var, value)| value.read_value().map(|v| (var, v)))
+            .collect();
+
+        for &(var, _) in &reads {
+            var.wait(&ctrl);
         }
-        while GLOBAL_SEQ_LOCK.compare_and_swap(self.snapshot, self.snapshot+1, Ordering::SeqCst) != self.snapshot {
-            match self.validate() {
-                None => { return false; }
-                Some(ss) => { self.snapshot = ss; }
+
+        let unchanged = reads.iter().all(|&(var, read)| var.version() == read.version);
+
+        if unchanged {
+            match deadline {
+                Some(deadline) => { ctrl.wait_until(deadline); }
+                None => ctrl.wait(),
+            }
+        }
+
+        for &(var, _) in &reads {
+            var.set_dead();
+        }
+    }
+
+    /// Start a new attempt.
+    ///
+    /// Returns `false`, if the deadline passed, before the backend let it start.
+    fn begin(&mut self) -> bool {
+        self.snapshot = if self.read_only {
+            backend::pin_snapshot()
+        } else {
+            match self.backend.begin(self.deadline) {
+                Some(snapshot) => snapshot,
+                None => return false,
+            }
+        };
+        if self.snapshot_isolated {
+            // The backend has been started anyway, because the global
+            // lock backend acquires its mutex there.
+            self.snapshot = backend::pin_snapshot();
+            self.pinned = Some(self.snapshot);
+        }
+        self.running = true;
+        true
+    }
+
+    /// End the current attempt without committing, but keep the log.
+    fn end(&mut self) {
+        if self.running {
+            self.running = false;
+            if self.read_only {
+                backend::unpin_snapshot(self.snapshot);
+            } else {
+                let backend = self.backend;
+                backend.abort(self);
             }
+            self.irrevocable = None;
+            self.serialized = false;
         }
-        let vars = mem::replace(&mut self.writevars, BTreeMap::new());
-        let mut write_vec = Vec::new();
+        // A commit, that panicked, has stopped running, but is still pinned.
+        if let Some(pinned) = self.pinned.take() {
+            backend::unpin_snapshot(pinned);
+        }
+    }
 
-        for (var, value) in &vars {
-            match value {
-                ref val => {
-                    let lock = var.value.write().unwrap();
-                    write_vec.push((val.clone(), lock));
+    /// End the current attempt and throw away the log.
+    fn clear(&mut self) {
+        self.end();
+        self.vars.clear();
+        self.released = 0;
+        self.tracked_reads = None;
+        self.commit_hooks.clear();
+        self.abort_hooks.clear();
+    }
+
+    /// Publish the writes of the transaction.
+    ///
+    /// Returns `Some(false)`, if the transaction has to be run again, and
+    /// `None`, if the deadline passed while an irrevocable transaction ran.
+    fn commit(&mut self) -> Option<bool> {
+        if self.read_only {
+            self.end();
+            return Some(true);
+        }
+        let writes = self.vars.values().any(LogVar::writes);
+        // An irrevocable transaction holds the gate already.
+        let permit = if self.irrevocable.is_none() && writes {
+            match backend::commit_permit(self.deadline) {
+                Some(permit) => Some(permit),
+                None => {
+                    self.end();
+                    return None;
                 }
             }
+        } else {
+            None
+        };
+        self.running = false;
+        let irrevocable = self.irrevocable.take();
+        self.serialized = false;
+        let backend = self.backend;
+        let committed = backend.commit(self);
+        if let Some(pinned) = self.pinned.take() {
+            backend::unpin_snapshot(pinned);
         }
-        for (value, mut lock) in write_vec {
-            *lock = value.clone();
+        assert!(committed || irrevocable.is_none(), "irrevocable transaction failed to commit");
+        mem::drop(permit);
+        mem::drop(irrevocable);
+        if !committed {
+            return Some(false);
         }
-        GLOBAL_SEQ_LOCK.store(self.snapshot + 2, Ordering::SeqCst);
-        true
+
+        // Wake up transactions, that are blocked on the written vars.
+        for (var, value) in &self.vars {
+            if value.writes() {
+                var.wake_all();
+            }
+        }
+        Some(true)
     }
 }
 
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.end();
+        mem::replace(&mut self.vars, Log::new()).recycle();
+    }
+}
+
+/// Panic, if the transaction has been aborted by an invariant.
+///
+/// For the ways to run a transaction, that can't return the violation.
+fn expect_valid<T, E>(result: Option<Result<T, CheckedError<E>>>) -> Option<Result<T, E>> {
+    result.map(|result| result.map_err(|err| match err {
+        CheckedError::Abort(err) => err,
+        CheckedError::Violated(name) => panic!("invariant `{}` violated", name),
+    }))
+}
 
-fn arc_to_address<T: ?Sized>(arc: &Arc<T>) -> usize {
-    &**arc as *const T as *const u32 as usize
+/// Run hooks in the order they have been registered.
+fn run_hooks(hooks: Vec<Hook>) {
+    for hook in hooks {
+        hook();
+    }
 }
 
-fn same_address<T: ?Sized>(a: &Arc<T>, b: &Arc<T>) -> bool {
-    arc_to_address(a) == arc_to_address(b)
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, mpsc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{backend, Transaction};
+    use super::super::test::{terminates, terminates_async, shared_backend, each_backend};
+    use super::super::{TVar, atomically, atomically_result, atomically_timeout, atomically_bounded};
+    use super::super::atomically_snapshot_isolated;
+    use super::super::{Backend, set_serialize_after, serialize_after};
+    use super::super::{retry, abort, Exhausted, StmResult};
+
+    #[test]
+    fn retry_blocks() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let terminated = terminates(300, move || {
+            atomically(|trans| {
+                if var.read(trans)? == 0 { retry() } else { Ok(()) }
+            })
+        });
+        assert!(!terminated);
+    }
+
+    #[test]
+    fn commit_wakes_retry() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let var2 = var.clone();
+        let terminated = terminates_async(1000, move || {
+            atomically(|trans| {
+                if var2.read(trans)? == 0 { retry() } else { Ok(()) }
+            })
+        }, || {
+            // Give the other thread time to block.
+            thread::sleep(Duration::from_millis(100));
+            atomically(|trans| var.write(trans, 42));
+        });
+        assert!(terminated);
+    }
+
+    #[test]
+    fn timeout_stops_retry() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            let terminated = terminates(1000, move || {
+                let result = atomically_timeout(Duration::from_millis(100), |trans| {
+                    if var.read(trans)? == 0 { retry() } else { Ok(()) }
+                });
+                assert_eq!(result, None);
+            });
+            assert!(terminated);
+        });
+    }
+
+    #[test]
+    fn timeout_stops_waiting_for_irrevocable() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            let (tx, rx) = mpsc::channel();
+            let irrevocable = thread::spawn(move || {
+                atomically(|trans| {
+                    trans.become_irrevocable()?;
+                    let _ = tx.send(());
+                    thread::sleep(Duration::from_millis(600));
+                    Ok(())
+                })
+            });
+            rx.recv().unwrap();
+
+            let terminated = terminates(400, move || {
+                let result = atomically_timeout(Duration::from_millis(100), |trans| {
+                    var.write(trans, 1)
+                });
+                assert_eq!(result, None);
+            });
+            assert!(terminated);
+            irrevocable.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn irrevocable_blocks_commits() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let writer = {
+            let var = var.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    atomically(|trans| var.modify(trans, |x| *x += 1));
+                }
+            })
+        };
+        for _ in 0..10 {
+            atomically(|trans| {
+                trans.become_irrevocable()?;
+                let before = var.read_atomic();
+                thread::sleep(Duration::from_millis(5));
+                assert_eq!(var.read_atomic(), before);
+                Ok(())
+            });
+        }
+        writer.join().unwrap();
+        assert_eq!(var.read_atomic(), 1000);
+    }
+
+    #[test]
+    fn timeout_stops_waiting_to_become_irrevocable() {
+        each_backend(|| {
+            let (tx, rx) = mpsc::channel();
+            let irrevocable = thread::spawn(move || {
+                atomically(|trans| {
+                    trans.become_irrevocable()?;
+                    let _ = tx.send(());
+                    thread::sleep(Duration::from_millis(600));
+                    Ok(())
+                })
+            });
+            rx.recv().unwrap();
+
+            let terminated = terminates(400, || {
+                let result = atomically_timeout(Duration::from_millis(100), |trans| {
+                    trans.become_irrevocable()
+                });
+                assert_eq!(result, None);
+            });
+            assert!(terminated);
+            irrevocable.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn bounded_retry_doesnt_block() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let var2 = var.clone();
+        let terminated = terminates(1000, move || {
+            let runs = AtomicUsize::new(0);
+            let result = atomically_bounded(3, |trans| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                if var2.read(trans)? == 0 { retry() } else { Ok(()) }
+            });
+            assert_eq!(result, Err(Exhausted));
+            assert_eq!(runs.load(Ordering::SeqCst), 3);
+        });
+        assert!(terminated);
+        assert_eq!(atomically_bounded(1, |trans| var.read(trans)), Ok(0));
+    }
+
+    #[test]
+    fn or_runs_second_on_retry() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let x = atomically(|trans| {
+            trans.or(|trans| {
+                var.write(trans, 1)?;
+                retry()
+            }, |trans| var.read(trans))
+        });
+        // The write of the first branch is rolled back.
+        assert_eq!(x, 0);
+        assert_eq!(var.read_atomic(), 0);
+    }
+
+    #[test]
+    fn or_blocks_on_both_branches() {
+        let _backend = shared_backend();
+        let a = TVar::new(0);
+        let b = TVar::new(0);
+        let (a2, b2) = (a.clone(), b.clone());
+        let terminated = terminates_async(1000, move || {
+            atomically(|trans| {
+                trans.or(|trans| {
+                    if a2.read(trans)? == 0 { retry() } else { Ok(()) }
+                }, |trans| {
+                    if b2.read(trans)? == 0 { retry() } else { Ok(()) }
+                })
+            })
+        }, || {
+            thread::sleep(Duration::from_millis(100));
+            atomically(|trans| b.write(trans, 1));
+        });
+        assert!(terminated);
+    }
+
+    #[test]
+    fn abort_discards_writes() {
+        let _backend = shared_backend();
+        let var = TVar::new(10);
+        let result: Result<(), &str> = atomically_result(|trans| {
+            var.write(trans, 0)?;
+            abort("insufficient funds")
+        });
+        assert_eq!(result, Err("insufficient funds"));
+        assert_eq!(var.read_atomic(), 10);
+    }
+
+    #[test]
+    fn catch_rolls_back_only_the_failed_part() {
+        let _backend = shared_backend();
+        let a = TVar::new(0);
+        let b = TVar::new(0);
+        let result: Result<i32, ()> = atomically_result(|trans| {
+            a.write(trans, 1)?;
+            trans.catch(|trans| {
+                b.write(trans, 1)?;
+                abort("no")
+            }, |_, err| {
+                assert_eq!(err, "no");
+                Ok(2)
+            })
+        });
+        assert_eq!(result, Ok(2));
+        assert_eq!(a.read_atomic(), 1);
+        assert_eq!(b.read_atomic(), 0);
+    }
+
+    #[test]
+    fn nested_and_savepoints() {
+        let _backend = shared_backend();
+        let a = TVar::new(1);
+        let b = TVar::new(2);
+        atomically(|trans| {
+            let ok: Result<(), ()> = trans.nested(|trans| a.write(trans, 10).map_err(Into::into))?;
+            assert!(ok.is_ok());
+            let err: Result<(), &str> = trans.nested(|trans| {
+                b.write(trans, 20)?;
+                abort("undo")
+            })?;
+            assert_eq!(err, Err("undo"));
+            assert_eq!(b.read(trans)?, 2);
+
+            let savepoint = trans.savepoint();
+            b.write(trans, 30)?;
+            trans.rollback_to(savepoint);
+            assert_eq!(b.read(trans)?, 2);
+            Ok(())
+        });
+        assert_eq!(a.read_atomic(), 10);
+        assert_eq!(b.read_atomic(), 2);
+    }
+
+    #[test]
+    fn hooks_run_once() {
+        let _backend = shared_backend();
+        let commits = Arc::new(AtomicUsize::new(0));
+        let aborts = Arc::new(AtomicUsize::new(0));
+        let var = TVar::new(0);
+        atomically(|trans| {
+            let commits = commits.clone();
+            trans.on_commit(move || { commits.fetch_add(1, Ordering::SeqCst); });
+            let aborts = aborts.clone();
+            trans.on_abort(move || { aborts.fetch_add(1, Ordering::SeqCst); });
+            var.write(trans, 1)
+        });
+        assert_eq!(commits.load(Ordering::SeqCst), 1);
+        assert_eq!(aborts.load(Ordering::SeqCst), 0);
+
+        let _: Result<(), ()> = atomically_result(|trans| {
+            let commits = commits.clone();
+            trans.on_commit(move || { commits.fetch_add(1, Ordering::SeqCst); });
+            let aborts = aborts.clone();
+            trans.on_abort(move || { aborts.fetch_add(1, Ordering::SeqCst); });
+            abort(())
+        });
+        assert_eq!(commits.load(Ordering::SeqCst), 1);
+        assert_eq!(aborts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panicking_commute_publishes_nothing() {
+        each_backend(|| {
+            let (a, b, c) = (TVar::new(0), TVar::new(0), TVar::new(0));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                atomically(|trans| {
+                    a.write(trans, 1)?;
+                    b.commute(trans, |_| panic!("commute failed"))?;
+                    c.write(trans, 1)
+                })
+            }));
+            assert!(result.is_err());
+
+            // The commit has released its locks.
+            let terminated = terminates(500, move || {
+                let values = atomically(|trans| Ok((a.read(trans)?, b.read(trans)?, c.read(trans)?)));
+                assert_eq!(values, (0, 0, 0));
+            });
+            assert!(terminated);
+        });
+    }
+
+    #[test]
+    fn panicking_commit_unpins_snapshot() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                atomically_snapshot_isolated(|trans| var.commute(trans, |_| panic!("commute failed")))
+            }));
+            assert!(result.is_err());
+            assert_eq!(backend::oldest_snapshot(), None);
+        });
+    }
+
+    #[test]
+    fn serialized_after_conflicts() {
+        each_backend(|| {
+            if backend::backend() == Backend::GlobalLock {
+                // Transactions never conflict under the global lock.
+                return;
+            }
+            let before = serialize_after();
+            set_serialize_after(Some(1));
+            let var = TVar::new(0);
+            let (serialized, attempts) = (Cell::new(false), Cell::new(0));
+            atomically(|trans| {
+                let x = var.read(trans)?;
+                if trans.attempt() == 1 {
+                    let var = var.clone();
+                    thread::spawn(move || var.write_atomic(x + 1)).join().unwrap();
+                }
+                serialized.set(trans.is_serialized());
+                attempts.set(trans.attempt());
+                var.write(trans, x + 10)
+            });
+            set_serialize_after(before);
+
+            assert!(serialized.get());
+            assert_eq!(attempts.get(), 2);
+            assert_eq!(var.read_atomic(), 11);
+        });
+    }
+
+    /// Run a transaction, that reads `a` and writes `b`, while `a` is written
+    /// concurrently during the first attempt, and return the number of attempts.
+    fn attempts_with_write_to<F>(release: F) -> usize
+    where F: Fn(&mut Transaction, &TVar<i32>) -> StmResult<i32>,
+    {
+        let (a, b) = (TVar::new(0), TVar::new(0));
+        let attempts = Cell::new(0);
+        atomically(|trans| {
+            let x = release(trans, &a)?;
+            if trans.attempt() == 1 {
+                let a = a.clone();
+                thread::spawn(move || a.write_atomic(x + 1)).join().unwrap();
+            }
+            attempts.set(trans.attempt());
+            b.write(trans, x)
+        });
+        attempts.get()
+    }
+
+    #[test]
+    fn released_vars_dont_conflict() {
+        each_backend(|| {
+            if backend::backend() == Backend::GlobalLock {
+                // Transactions never conflict under the global lock.
+                return;
+            }
+            assert_eq!(attempts_with_write_to(|trans, a| a.read(trans)), 2);
+            assert_eq!(attempts_with_write_to(|trans, a| {
+                let x = a.read(trans)?;
+                trans.release(a);
+                Ok(x)
+            }), 1);
+            assert_eq!(attempts_with_write_to(|trans, a| trans.release_reads(|trans| a.read(trans))), 1);
+        });
+    }
+
+    #[test]
+    fn retry_waits_for_released_vars() {
+        let _backend = shared_backend();
+        let var = TVar::new(0);
+        let var2 = var.clone();
+        let terminated = terminates_async(1000, move || {
+            atomically(|trans| {
+                let x = var2.read(trans)?;
+                trans.release(&var2);
+                if x == 0 { retry() } else { Ok(()) }
+            })
+        }, || {
+            // Give the other thread time to block.
+            thread::sleep(Duration::from_millis(100));
+            var.write_atomic(42);
+        });
+        assert!(terminated);
+    }
+}
@@ -0,0 +1,68 @@
+//! Waiting for commits of other transactions to finish.
+//!
+//! Commits are short, so a waiting thread spins first. If the committing
+//! thread has been descheduled, spinning only burns the time it needs to
+//! finish, so the waiting thread yields and finally parks, until a commit wakes it.
+
+use std::sync::{Mutex, Condvar, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::hint;
+use std::thread;
+
+/// Number of rounds spent spinning, before yielding.
+///
+/// Round `i` spins `2^i` times.
+const SPIN_ROUNDS: usize = 6;
+
+/// Number of rounds spent yielding, before parking.
+const YIELD_ROUNDS: usize = 10;
+
+/// Number of threads parked in `wait_for`.
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+static PARKING_LOT: Mutex<()> = Mutex::new(());
+
+static COMMIT_FINISHED: Condvar = Condvar::new();
+
+/// Wait until `ready` returns a value.
+///
+/// `ready` must become true through a commit, that calls `commit_finished`
+/// afterwards. Otherwise a parked thread isn't woken up.
+pub fn wait_for<T, F>(mut ready: F) -> T
+where F: FnMut() -> Option<T>,
+{
+    for round in 0..SPIN_ROUNDS + YIELD_ROUNDS {
+        if let Some(t) = ready() {
+            return t;
+        }
+        if round < SPIN_ROUNDS {
+            for _ in 0..1 << round {
+                hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+    }
+
+    let mut guard = PARKING_LOT.lock().unwrap_or_else(PoisonError::into_inner);
+    // Announce the thread before checking again. A commit, that
+    // doesn't see it, has finished before the check.
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        if let Some(t) = ready() {
+            PARKED.fetch_sub(1, Ordering::SeqCst);
+            return t;
+        }
+        guard = COMMIT_FINISHED.wait(guard).unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Wake up all threads parked in `wait_for`.
+///
+/// Called at the end of every commit, after the locks have been released.
+pub fn commit_finished() {
+    if PARKED.load(Ordering::SeqCst) != 0 {
+        let _guard = PARKING_LOT.lock().unwrap_or_else(PoisonError::into_inner);
+        COMMIT_FINISHED.notify_all();
+    }
+}
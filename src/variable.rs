@@ -1,47 +1,299 @@
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Weak, Mutex, RwLock, RwLockReadGuard, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::mem;
 use std::cmp;
+use std::collections::VecDeque;
 use std::any::Any;
-use std::marker::PhantomData;
 
 use super::result::*;
 use super::Transaction;
+use super::transaction::control_block::ControlBlock;
+use super::transaction::log_var::ArcAny;
+use super::transaction::wait;
+use super::transaction::backend;
 
-pub struct VarControlBlock {
-    pub value: RwLock<Arc<Any + Send + Sync>>,
+/// Number of dead waiters after which the waiter list gets cleaned up.
+const MAX_DEAD_THREADS: usize = 64;
+
+/// Bit of the versioned lock, that is set while a transaction commits to the var.
+pub const LOCKED: usize = 1;
+
+/// A var with its value of type `T`.
+pub struct VarControlBlock<T> {
+    /// Threads that called `retry` after reading this var.
+    ///
+    /// They get woken up, when a transaction writes to it.
+    waiting_threads: Mutex<Vec<Weak<ControlBlock>>>,
+
+    /// Number of threads that stopped waiting on this var
+    /// and may still linger in `waiting_threads`.
+    dead_threads: AtomicUsize,
+
+    /// Versioned lock.
+    ///
+    /// The `LOCKED` bit is set while a transaction commits to the var.
+    /// The remaining bits hold the global clock of the last commit.
+    version: AtomicUsize,
+
+    value: RwLock<Arc<T>>,
+
+    /// Values, that have been overwritten while a read-only transaction
+    /// was running, together with their versions. Oldest first.
+    ///
+    /// Values, that are not needed anymore, are dropped by the next commit.
+    history: Mutex<VecDeque<(usize, Arc<T>)>>,
 }
 
-impl VarControlBlock {
-    pub fn new<T>(val: T) -> Arc<VarControlBlock>
-        where T: Any + Sync + Send
-    {
+/// A var of any type.
+///
+/// The log of a transaction holds vars of different types, so
+/// it only sees this part of them. Values are type-erased as well.
+pub trait Var: Send + Sync {
+    /// Register a blocked transaction, that waits for a change of this var.
+    fn wait(&self, thread: &Arc<ControlBlock>);
+
+    /// Wake up all threads, that are waiting for a change of this var.
+    ///
+    /// Must be called after the new value has been stored.
+    fn wake_all(&self);
+
+    /// Inform the var, that a thread registered with `wait` stopped waiting.
+    ///
+    /// Dead entries are removed once in a while, so that vars that are rarely
+    /// written don't accumulate an unbounded waiter list.
+    fn set_dead(&self);
+
+    /// Get the versioned lock of the var.
+    fn version(&self) -> usize;
+
+    /// Lock the var for a commit and return its version.
+    ///
+    /// Waits while another transaction commits to the var. Commits lock
+    /// their vars in the order of their addresses, so this can't deadlock.
+    fn lock_version(&self) -> usize;
+
+    /// Set the version of the var.
+    ///
+    /// This also releases a lock taken by `lock_version`.
+    fn set_version(&self, version: usize);
+
+    /// Get the current value.
+    fn value_any(&self) -> ArcAny;
+
+    /// Replace the value with one from the log of a transaction.
+    ///
+    /// See `VarControlBlock::publish`.
+    fn publish_any(&self, value: ArcAny, version: usize, oldest_snapshot: Option<usize>) -> bool;
+
+    /// Drop old values, that no pinned snapshot can see anymore.
+    ///
+    /// Returns `false`, if no old value is left.
+    fn prune_history(&self) -> bool;
+}
+
+impl<T> VarControlBlock<T>
+    where T: Any + Sync + Send
+{
+    pub fn new(val: T) -> Arc<VarControlBlock<T>> {
         let ctrl = VarControlBlock {
+            waiting_threads: Mutex::new(Vec::new()),
+            dead_threads: AtomicUsize::new(0),
+            version: AtomicUsize::new(0),
             value: RwLock::new(Arc::new(val)),
+            history: Mutex::new(VecDeque::new()),
         };
         Arc::new(ctrl)
     }
 
-    fn get_address(&self) -> usize {
-        self as *const VarControlBlock as usize
+    /// Lock the value for reading.
+    ///
+    /// The lock only guards the exchange of an `Arc`, so a panic can't
+    /// leave the value half written. Poisoning is therefore ignored.
+    pub fn read_value(&self) -> RwLockReadGuard<Arc<T>> {
+        self.value.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the value, that was current at `snapshot`, and its version.
+    ///
+    /// The snapshot must be pinned, so that no commit drops the value.
+    pub fn read_at(&self, snapshot: usize) -> (Arc<T>, usize) {
+        loop {
+            // The commit may publish a value, that the snapshot must see.
+            let version = wait::wait_for(|| {
+                let version = self.version();
+                if version & LOCKED == 0 { Some(version) } else { None }
+            });
+            if version > snapshot {
+                break;
+            }
+            let value = self.read_value().clone();
+            if self.version() == version {
+                return (value, version);
+            }
+        }
+        // The old value is added before the version changes.
+        let history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+        history.iter().rev()
+            .find(|&&(version, _)| version <= snapshot)
+            .map(|&(version, ref value)| (value.clone(), version))
+            .expect("value of a pinned snapshot has been dropped")
+    }
+
+    /// Replace the value and set the version, that it was committed with.
+    ///
+    /// The old value is kept in the history, if a read-only transaction
+    /// with a snapshot of at least `oldest_snapshot` may still need it.
+    /// Values, that no running snapshot can see anymore, are dropped.
+    ///
+    /// Returns `true`, if the history was empty before and isn't anymore.
+    /// The var must then be passed to `prune_history`, once the oldest
+    /// snapshot has ended. See `backend::publish`.
+    pub fn publish(&self, value: Arc<T>, version: usize, oldest_snapshot: Option<usize>) -> bool {
+        // Poisoning is ignored for the same reason as in `read_value`.
+        let mut current = self.value.write().unwrap_or_else(PoisonError::into_inner);
+        let mut history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+        let old = mem::replace(&mut *current, value);
+        let was_empty = history.is_empty();
+
+        if oldest_snapshot.is_some() {
+            history.push_back((self.version() & !LOCKED, old));
+        }
+        prune(&mut history, version, oldest_snapshot);
+        self.set_version(version);
+        was_empty && !history.is_empty()
+    }
+
+    /// Get the type back for a value from the log of this var.
+    ///
+    /// Panics, if `value` doesn't belong to a var of type `T`.
+    pub fn cast(value: ArcAny) -> Arc<T> {
+        value.downcast::<T>().unwrap_or_else(|_| panic!("value of the wrong type for a TVar"))
+    }
+
+    /// Get mutable access to a value from the log of this var, if it isn't shared.
+    ///
+    /// Panics like `cast`.
+    pub fn cast_mut(value: &mut ArcAny) -> Option<&mut T> {
+        Arc::get_mut(value).map(|value| {
+            value.downcast_mut::<T>().expect("value of the wrong type for a TVar")
+        })
     }
 }
 
-impl PartialEq for VarControlBlock {
+impl<T> Var for VarControlBlock<T>
+    where T: Any + Sync + Send
+{
+    fn wait(&self, thread: &Arc<ControlBlock>) {
+        let mut guard = self.waiting_threads.lock().unwrap_or_else(PoisonError::into_inner);
+        guard.push(Arc::downgrade(thread));
+    }
+
+    fn wake_all(&self) {
+        // Atomically take all waiting threads from the var.
+        let threads = {
+            let mut guard = self.waiting_threads.lock().unwrap_or_else(PoisonError::into_inner);
+            mem::replace(&mut *guard, Vec::new())
+        };
+        self.dead_threads.store(0, Ordering::SeqCst);
+
+        // Release all threads that are still alive.
+        for thread in threads.iter().filter_map(Weak::upgrade) {
+            thread.set_changed();
+        }
+    }
+
+    fn set_dead(&self) {
+        let deads = self.dead_threads.fetch_add(1, Ordering::Relaxed);
+        if deads >= MAX_DEAD_THREADS {
+            let mut guard = self.waiting_threads.lock().unwrap_or_else(PoisonError::into_inner);
+            self.dead_threads.store(0, Ordering::SeqCst);
+            guard.retain(|t| t.upgrade().is_some());
+        }
+    }
+
+    fn version(&self) -> usize {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    fn lock_version(&self) -> usize {
+        wait::wait_for(|| {
+            let version = self.version.load(Ordering::SeqCst);
+            if version & LOCKED == 0 &&
+               self.version.compare_exchange(version, version | LOCKED,
+                                             Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                Some(version)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn set_version(&self, version: usize) {
+        self.version.store(version, Ordering::SeqCst);
+    }
+
+    fn value_any(&self) -> ArcAny {
+        self.read_value().clone()
+    }
+
+    fn publish_any(&self, value: ArcAny, version: usize, oldest_snapshot: Option<usize>) -> bool {
+        self.publish(VarControlBlock::<T>::cast(value), version, oldest_snapshot)
+    }
+
+    fn prune_history(&self) -> bool {
+        let mut history = self.history.lock().unwrap_or_else(PoisonError::into_inner);
+        // Only look for the oldest snapshot while holding the lock. A commit,
+        // that has kept a value for a newer snapshot, has seen it pinned.
+        let oldest = backend::oldest_snapshot();
+        prune(&mut history, self.version() & !LOCKED, oldest);
+        !history.is_empty()
+    }
+}
+
+/// Drop the old values, that no snapshot at or after `oldest` can see.
+///
+/// `current` is the version of the current value.
+fn prune<T>(history: &mut VecDeque<(usize, Arc<T>)>, current: usize, oldest: Option<usize>) {
+    match oldest {
+        None => history.clear(),
+        Some(oldest) => {
+            // An old value is visible to snapshots up to the version of
+            // its successor, so it can go once the successor is visible
+            // to the oldest snapshot.
+            while !history.is_empty() {
+                let next = history.get(1).map_or(current, |&(next, _)| next);
+                if next > oldest {
+                    break;
+                }
+                history.pop_front();
+            }
+        }
+    }
+}
+
+impl Var {
+    /// Identify the var without holding on to it.
+    pub fn address(&self) -> usize {
+        self as *const Var as *const u8 as usize
+    }
+}
+
+impl PartialEq for Var {
     fn eq(&self, other: &Self) -> bool {
-        self.get_address() == other.get_address()
+        self.address() == other.address()
     }
 }
 
-impl Eq for VarControlBlock {}
+impl Eq for Var {}
 
-impl Ord for VarControlBlock {
+impl Ord for Var {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.get_address().cmp(&other.get_address())
+        self.address().cmp(&other.address())
     }
 }
 
-impl PartialOrd for VarControlBlock {
+impl PartialOrd for Var {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
@@ -49,8 +301,7 @@ impl PartialOrd for VarControlBlock {
 
 #[derive(Clone)]
 pub struct TVar<T> {
-    control_block: Arc<VarControlBlock>,
-    _marker: PhantomData<T>,
+    control_block: Arc<VarControlBlock<T>>,
 }
 
 impl<T> TVar<T>
@@ -59,36 +310,188 @@ impl<T> TVar<T>
     pub fn new(val: T) -> TVar<T> {
         TVar {
             control_block: VarControlBlock::new(val),
-            _marker: PhantomData,
         }
     }
 
     pub fn read_atomic(&self) -> T {
-        let val = self.read_ref_atomic();
-
-        (&*val as &Any)
-            .downcast_ref::<T>()
-            .expect("wrong type in Var<T>")
-            .clone()
+        let val = self.control_block.read_value().clone();
+        (*val).clone()
     }
 
-    pub fn read_ref_atomic(&self) -> Arc<Any + Send + Sync> {
+    pub fn read_ref_atomic(&self) -> Arc<T> {
         self.control_block
-            .value
-            .read()
-            .unwrap()
+            .read_value()
             .clone()
     }
 
+    /// Write the value outside of a transaction.
+    ///
+    /// This commits a transaction, that only writes this var, so running
+    /// transactions see the write like any other commit and transactions
+    /// blocked on the var are woken up. Like `atomically`, it must not be
+    /// called inside of a transaction.
+    pub fn write_atomic(&self, value: T) {
+        Transaction::run(|trans| self.write(trans, value.clone()));
+    }
+
+    /// Replace the value outside of a transaction and return the old one.
+    ///
+    /// See `write_atomic`.
+    pub fn swap_atomic(&self, value: T) -> T {
+        Transaction::run(|trans| {
+            let old = self.read(trans)?;
+            self.write(trans, value.clone())?;
+            Ok(old)
+        })
+    }
+
+    /// Change the value in place outside of a transaction.
+    ///
+    /// `f` runs again, if another transaction writes the var in between.
+    /// See `write_atomic`.
+    pub fn modify_atomic<F, R>(&self, f: F) -> R
+    where F: Fn(&mut T) -> R
+    {
+        Transaction::run(|trans| self.modify(trans, &f))
+    }
+
     pub fn read(&self, transaction: &mut Transaction) -> StmResult<T> {
         transaction.read(&self)
     }
 
+    /// Read the value without cloning it.
+    pub fn read_ref(&self, transaction: &mut Transaction) -> StmResult<Arc<T>> {
+        transaction.read_ref(&self)
+    }
+
+    /// Call `f` with a reference to the value.
+    pub fn with<F, R>(&self, transaction: &mut Transaction, f: F) -> StmResult<R>
+    where F: FnOnce(&T) -> R
+    {
+        let value = transaction.read_ref(&self)?;
+        Ok(f(&value))
+    }
+
     pub fn write(&self, transaction: &mut Transaction, value: T) -> StmResult<()> {
         transaction.write(&self, value)
     }
+
+    /// Read the value and make sure, that it is still current, when the transaction commits.
+    ///
+    /// See `Transaction::ensure`.
+    pub fn ensure(&self, transaction: &mut Transaction) -> StmResult<T> {
+        transaction.ensure(&self)
+    }
+
+    /// Update the value with `f` on commit, without conflicting
+    /// with other updates. See `Transaction::commute`.
+    pub fn commute<F>(&self, transaction: &mut Transaction, f: F) -> StmResult<()>
+    where F: Fn(&mut T) + Send + Sync + 'static
+    {
+        transaction.commute(&self, f)
+    }
+
+    /// Change the value in place. See `Transaction::modify`.
+    pub fn modify<F, R>(&self, transaction: &mut Transaction, f: F) -> StmResult<R>
+    where F: FnOnce(&mut T) -> R
+    {
+        transaction.modify(&self, f)
+    }
     
-    pub fn control_block(&self) -> &Arc<VarControlBlock> {
+    pub fn control_block(&self) -> &Arc<VarControlBlock<T>> {
         &self.control_block
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::TVar;
+    use super::super::test::{each_backend, async};
+    use super::super::transaction::backend;
+    use super::super::{atomically, atomically_read_only, retry};
+
+    fn history_len(var: &TVar<i32>) -> usize {
+        var.control_block().history.lock().unwrap().len()
+    }
+
+    #[test]
+    fn history_is_dropped_after_snapshot() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            let snapshot = backend::pin_snapshot();
+            var.write_atomic(1);
+            var.write_atomic(2);
+            assert_eq!(history_len(&var), 2);
+
+            backend::unpin_snapshot(snapshot);
+            assert_eq!(history_len(&var), 0);
+        });
+    }
+
+    #[test]
+    fn long_read_only_never_restarts() {
+        each_backend(|| {
+            let (a, b) = (TVar::new(0), TVar::new(0));
+            let runs = Cell::new(0);
+            let values = atomically_read_only(|trans| {
+                runs.set(runs.get() + 1);
+                let x = a.read(trans)?;
+                let (a2, b2) = (a.clone(), b.clone());
+                thread::spawn(move || {
+                    for i in 1..100 {
+                        a2.write_atomic(i);
+                        b2.write_atomic(i);
+                    }
+                }).join().unwrap();
+                Ok((x, b.read(trans)?))
+            });
+            assert_eq!(runs.get(), 1);
+            assert_eq!(values, (0, 0));
+        });
+    }
+
+    #[test]
+    fn write_atomic_wakes_retry() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            let var2 = var.clone();
+            let seen = async(1000, move || {
+                atomically(|trans| {
+                    let x = var2.read(trans)?;
+                    if x == 0 { retry() } else { Ok(x) }
+                })
+            }, || {
+                // Give the other thread time to block.
+                thread::sleep(Duration::from_millis(100));
+                var.write_atomic(42);
+            });
+            assert_eq!(seen, Some(42));
+        });
+    }
+
+    #[test]
+    fn swap_atomic_returns_old_value() {
+        each_backend(|| {
+            let var = TVar::new(0);
+            assert_eq!(var.swap_atomic(1), 0);
+            assert_eq!(var.swap_atomic(2), 1);
+            assert_eq!(var.read_atomic(), 2);
+
+            // Every value is returned by exactly one swap, if no swap is lost.
+            let children: Vec<_> = (0..4).map(|t| {
+                let var = var.clone();
+                thread::spawn(move || {
+                    (0..100).map(|i| var.swap_atomic(3 + t * 100 + i)).collect::<Vec<_>>()
+                })
+            }).collect();
+            let mut old: Vec<_> = children.into_iter().flat_map(|c| c.join().unwrap()).collect();
+            old.push(var.read_atomic());
+            old.sort();
+            assert_eq!(old, (2..403).collect::<Vec<_>>());
+        });
+    }
+}